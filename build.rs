@@ -1,5 +0,0 @@
-use lalrpop;
-
-fn main() {
-    lalrpop::process_root().unwrap();
-}
@@ -0,0 +1,149 @@
+use super::{Program, Flow};
+use crate::instructions::{OptionalInst, Instruction};
+
+/// A run of instructions that's only entered at the start and only left at the end.
+/// Runs of unknown bytes are kept together in blocks of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub start: usize,
+    /// One past the last instruction.
+    pub end: usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Falling through to the next block.
+    Next,
+    Jump,
+    Call,
+    /// From a `call` to where the subroutine returns to.
+    AfterCall,
+    Fork(u8)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+    /// Whether the edge is only taken depending on a `?`.
+    pub conditional: bool
+}
+
+/// Basic blocks and the edges between them. Blocks and edges are referred to by index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    pub edges: Vec<Edge>,
+    block_of: Vec<usize>
+}
+
+impl Cfg {
+    pub fn new(program: &Program) -> Cfg {
+        let is_byte = |idx: usize| matches!(program.instructions.get(idx), Some(OptionalInst::Byte(_)));
+        let mut leader = vec![false; program.len()];
+        for idx in 0..program.len() {
+            let next = idx + 1;
+            let flows = program.successors(idx);
+            for flow in &flows {
+                match *flow {
+                    Flow::Jump(target) | Flow::Call { target, .. } | Flow::Fork { target, .. } => leader[target] = true,
+                    _ => ()
+                }
+            }
+            if program.label_at(idx).is_some() || (idx > 0 && is_byte(idx) != is_byte(idx - 1)) {
+                leader[idx] = true;
+            }
+            // Anything other than plainly continuing ends the block, and so does a `?` or what it guards.
+            let plain = flows == [Flow::Next(next)] && !program.is_guarded(idx) && program.instruction(idx) != Some(&Instruction::If);
+            if next < program.len() && !plain && !is_byte(idx) {
+                leader[next] = true;
+            }
+        }
+
+        let mut blocks = Vec::new();
+        let mut block_of = Vec::with_capacity(program.len());
+        for (idx, &leader) in leader.iter().enumerate() {
+            if idx == 0 || leader {
+                blocks.push(Block { start: idx, end: idx });
+            }
+            blocks.last_mut().unwrap().end = idx + 1;
+            block_of.push(blocks.len() - 1);
+        }
+
+        let mut edges = Vec::new();
+        for (from, block) in blocks.iter().enumerate() {
+            let last = block.end - 1;
+            let conditional = program.is_guarded(last) || program.instruction(last) == Some(&Instruction::If);
+            for flow in program.successors(last) {
+                let (to, kind) = match flow {
+                    Flow::Next(next) => (next, EdgeKind::Next),
+                    Flow::Jump(target) => (target, EdgeKind::Jump),
+                    Flow::Call { target, ret } => {
+                        edges.push(Edge { from, to: block_of[ret], kind: EdgeKind::AfterCall, conditional: false });
+                        (target, EdgeKind::Call)
+                    },
+                    Flow::Fork { track, target } => (target, EdgeKind::Fork(track)),
+                    Flow::Return => continue
+                };
+                edges.push(Edge { from, to: block_of[to], kind, conditional });
+            }
+        }
+        Cfg { blocks, edges, block_of }
+    }
+
+    /// The block that the instruction at `idx` is in.
+    pub fn block_of(&self, idx: usize) -> usize {
+        self.block_of[idx]
+    }
+
+    /// Edges leaving the block at `block`.
+    pub fn successors(&self, block: usize) -> impl Iterator<Item=&Edge> {
+        self.edges.iter().filter(move |edge| edge.from == block)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::assemble;
+    use std::path::Path;
+
+    #[test]
+    fn test_cfg() {
+        let source = "
+            start:
+                fork 1, other
+                rest 1
+            loop:
+                note 60, 100, 48
+                ? jump loop
+                call sub
+                end_track
+            other:
+                end_track
+            sub:
+                ret
+            .bytes 1, 2, 3
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let cfg = Cfg::new(&program);
+
+        let blocks: Vec<(usize, usize)> = cfg.blocks.iter().map(|b| (b.start, b.end)).collect();
+        assert_eq!(blocks, [(0, 2), (2, 3), (3, 6), (6, 7), (7, 8), (8, 9), (9, 11), (11, 13), (13, 16)]);
+
+        let edges: Vec<(usize, usize, EdgeKind, bool)> = cfg.edges.iter().map(|e| (e.from, e.to, e.kind, e.conditional)).collect();
+        assert_eq!(edges, [
+            (0, 6, EdgeKind::Fork(1), false),
+            (0, 1, EdgeKind::Next, false),
+            (1, 2, EdgeKind::Next, false),
+            (2, 3, EdgeKind::Next, true),
+            (3, 2, EdgeKind::Jump, true),
+            (3, 4, EdgeKind::Next, true),
+            (4, 5, EdgeKind::AfterCall, false),
+            (4, 7, EdgeKind::Call, false),
+        ]);
+        assert_eq!(cfg.block_of(14), 8);
+    }
+}
@@ -0,0 +1,241 @@
+use super::Program;
+use crate::instructions::{OptionalInst, Destination, bin::is_generated_label};
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Sections bigger than this (in instructions on one side, times instructions on the other)
+/// that still differ after trimming what they start and end with are treated as rewritten.
+const MAX_TABLE: usize = 4_000_000;
+
+/// One difference between two versions of a section. Indices are into each program's instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// In the old program, but not the new one.
+    Removed(usize),
+    /// In the new program, but not the old one.
+    Inserted(usize),
+    /// Replaced by something else in the same place.
+    Changed(usize, usize)
+}
+
+/// The differences between the instructions that follow a label in two programs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionDiff {
+    /// The label that starts the section, or `None` for what comes before the first one.
+    pub label: Option<String>,
+    /// Where the section starts in each program, if it's in it at all.
+    pub old: Option<usize>,
+    pub new: Option<usize>,
+    pub changes: Vec<Change>
+}
+
+/// A run of instructions from one label in LABL up to the next.
+struct Section<'a> {
+    label: Option<&'a str>,
+    start: usize,
+    body: Range<usize>
+}
+
+/// Zero bytes at the very end are padding, and left out.
+fn sections<'a>(program: &Program<'a>) -> Vec<Section<'a>> {
+    let padding = program.instructions.iter().rev().take_while(|inst| **inst == OptionalInst::Byte(0)).count();
+    let len = program.len() - padding;
+    let mut sections = vec![Section { label: None, start: 0, body: 0..len }];
+    for idx in 0..len {
+        match program.label_at(idx) {
+            Some(name) if !is_generated_label(name) => {
+                sections.last_mut().unwrap().body.end = idx;
+                sections.push(Section { label: Some(name), start: idx, body: idx + 1..len });
+            },
+            _ => ()
+        }
+    }
+    sections
+}
+
+/// What an instruction is compared by. Made up labels are named after addresses that move
+/// whenever anything before them changes, so they're all treated the same here and checked
+/// against each other once everything's lined up.
+fn key(inst: &OptionalInst) -> String {
+    match inst {
+        OptionalInst::Label(name) if is_generated_label(name) => "loc:".into(),
+        OptionalInst::Instruction(inst) => match inst.destination() {
+            Some(Destination::Label(name)) if is_generated_label(name) => {
+                let mut inst = inst.clone();
+                *inst.destination_mut().unwrap() = Destination::Label("loc".into());
+                inst.to_string()
+            },
+            _ => inst.to_string()
+        },
+        _ => inst.to_string()
+    }
+}
+
+/// Lines up two lists, giving pairs of indices that match and `None` opposite anything that doesn't.
+fn align<T: PartialEq>(old: &[T], new: &[T]) -> Vec<(Option<usize>, Option<usize>)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (n, m) = (old.len() - prefix - suffix, new.len() - prefix - suffix);
+
+    let mut pairs: Vec<_> = (0..prefix).map(|idx| (Some(idx), Some(idx))).collect();
+    if (n + 1) * (m + 1) > MAX_TABLE {
+        pairs.extend((prefix..prefix + n).map(|idx| (Some(idx), None)));
+        pairs.extend((prefix..prefix + m).map(|idx| (None, Some(idx))));
+    } else {
+        // Longest common subsequence of what's left, then walked back from the start.
+        let (a, b) = (&old[prefix..prefix + n], &new[prefix..prefix + m]);
+        let mut table = vec![0u32; (n + 1) * (m + 1)];
+        let at = |i: usize, j: usize| i * (m + 1) + j;
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                table[at(i, j)] = if a[i] == b[j] {
+                    table[at(i + 1, j + 1)] + 1
+                } else {
+                    table[at(i + 1, j)].max(table[at(i, j + 1)])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && a[i] == b[j] {
+                pairs.push((Some(prefix + i), Some(prefix + j)));
+                i += 1;
+                j += 1;
+            } else if j == m || (i < n && table[at(i + 1, j)] >= table[at(i, j + 1)]) {
+                pairs.push((Some(prefix + i), None));
+                i += 1;
+            } else {
+                pairs.push((None, Some(prefix + j)));
+                j += 1;
+            }
+        }
+    }
+    pairs.extend((0..suffix).map(|idx| (Some(old.len() - suffix + idx), Some(new.len() - suffix + idx))));
+    pairs
+}
+
+/// Turns unmatched runs between matches into changes, pairing removals up with insertions.
+fn changes(pairs: &[(Option<usize>, Option<usize>)]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let mut flush = |removed: &mut Vec<usize>, inserted: &mut Vec<usize>| {
+        let paired = removed.len().min(inserted.len());
+        changes.extend(removed.iter().zip(inserted.iter()).map(|(&a, &b)| Change::Changed(a, b)));
+        changes.extend(removed[paired..].iter().map(|&a| Change::Removed(a)));
+        changes.extend(inserted[paired..].iter().map(|&b| Change::Inserted(b)));
+        removed.clear();
+        inserted.clear();
+    };
+    let (mut removed, mut inserted) = (Vec::new(), Vec::new());
+    for pair in pairs {
+        match *pair {
+            (Some(a), None) => removed.push(a),
+            (None, Some(b)) => inserted.push(b),
+            _ => flush(&mut removed, &mut inserted)
+        }
+    }
+    flush(&mut removed, &mut inserted);
+    changes
+}
+
+/// Compares two programs label by label. Sections are matched up by the name of the label in LABL
+/// that starts them, and labels without one are compared by what they point at rather than by name.
+/// Only sections with differences are returned, in the order of the new program, with removed
+/// sections after whatever they followed in the old one.
+pub fn diff(old: &Program, new: &Program) -> Vec<SectionDiff> {
+    let (old_sections, new_sections) = (sections(old), sections(new));
+
+    // Every section of `old`, with sections only in `new` slotted in after whatever they follow there.
+    let mut pairs: Vec<(Option<&Section>, Option<&Section>)> = old_sections.iter().map(|section| (Some(section), None)).collect();
+    let mut cursor = 0;
+    for section in &new_sections {
+        match pairs.iter().position(|(old, _)| old.map(|old| old.label) == Some(section.label)) {
+            Some(pos) => {
+                pairs[pos].1 = Some(section);
+                cursor = pos + 1;
+            },
+            None => {
+                pairs.insert(cursor, (None, Some(section)));
+                cursor += 1;
+            }
+        }
+    }
+
+    let keys = |program: &Program, section: Option<&Section>| -> Vec<String> {
+        section.map(|section| program.instructions[section.body.clone()].iter().map(key).collect()).unwrap_or_default()
+    };
+    let aligned: Vec<Vec<(Option<usize>, Option<usize>)>> = pairs.iter().map(|(a, b)| {
+        let offset = |section: &Option<&Section>, idx: Option<usize>| idx.map(|idx| section.unwrap().body.start + idx);
+        align(&keys(old, *a), &keys(new, *b)).into_iter().map(|(i, j)| (offset(a, i), offset(b, j))).collect()
+    }).collect();
+
+    // Made up labels that line up are the same label.
+    let mut renamed = HashMap::new();
+    for &(a, b) in aligned.iter().flatten() {
+        if let (Some(a), Some(b)) = (a, b) {
+            if let (Some(from), Some(to)) = (old.label_at(a), new.label_at(b)) {
+                renamed.insert(from, to);
+            }
+        }
+    }
+    let same_target = |a: usize, b: usize| match (old.instruction(a).and_then(|i| i.destination()), new.instruction(b).and_then(|i| i.destination())) {
+        (Some(Destination::Label(from)), Some(Destination::Label(to))) if is_generated_label(from) =>
+            renamed.get(from.as_str()) == Some(&to.as_str()),
+        _ => true
+    };
+
+    pairs.iter().zip(aligned).filter_map(|((a, b), aligned)| {
+        let aligned: Vec<_> = aligned.into_iter().flat_map(|pair| match pair {
+            (Some(a), Some(b)) if !same_target(a, b) => vec![(Some(a), None), (None, Some(b))],
+            pair => vec![pair]
+        }).collect();
+        let changes = changes(&aligned);
+        if changes.is_empty() && a.is_some() && b.is_some() {
+            return None;
+        }
+        let label = a.or(*b).and_then(|section| section.label).map(String::from);
+        Some(SectionDiff { label, old: a.map(|section| section.start), new: b.map(|section| section.start), changes })
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::assemble;
+    use std::path::Path;
+
+    #[test]
+    fn test_diff() {
+        let old = "
+            main:
+                set Volume = 100
+                loc_000010:
+                rest 48
+                jump loc_000010
+            sub:
+                ret
+            gone:
+                end_track
+        ";
+        let new = "
+            main:
+                set Volume = 90
+                note 60, 100, 48
+                loc_000020:
+                rest 48
+                jump loc_000020
+            sub:
+                ret
+            added:
+                end_track
+        ";
+        let old = assemble(old, Path::new("")).unwrap().rseq;
+        let new = assemble(new, Path::new("")).unwrap().rseq;
+        let found = diff(&Program::new(&old.instructions), &Program::new(&new.instructions));
+        assert_eq!(found, [
+            SectionDiff { label: Some("main".into()), old: Some(0), new: Some(0), changes: vec![Change::Changed(1, 1), Change::Inserted(2)] },
+            SectionDiff { label: Some("added".into()), old: None, new: Some(8), changes: vec![Change::Inserted(9)] },
+            SectionDiff { label: Some("gone".into()), old: Some(7), new: None, changes: vec![Change::Removed(8)] },
+        ]);
+    }
+}
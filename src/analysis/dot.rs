@@ -0,0 +1,92 @@
+use super::{Program, Flow, cfg::{Cfg, EdgeKind}, tracks::TrackMap};
+use crate::instructions::OptionalInst;
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Write};
+
+/// Escapes a string for use inside a quoted DOT label.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn block_label(program: &Program, start: usize, end: usize) -> String {
+    let instructions = &program.instructions[start..end];
+    if let Some(OptionalInst::Byte(_)) = instructions.first() {
+        return format!(".bytes ({} bytes)\\l", instructions.len());
+    }
+    let mut label = String::new();
+    for inst in instructions {
+        label.push_str(&escape(&inst.to_string()));
+        label.push_str("\\l");
+    }
+    label
+}
+
+/// Writes the control flow graph as a Graphviz digraph, with one node per basic block.
+/// If `include` is given, only blocks with an instruction in it are written.
+pub fn write_cfg(out: &mut impl Write, program: &Program, cfg: &Cfg, include: Option<&BTreeSet<usize>>) -> fmt::Result {
+    let included = |block: usize| {
+        let block = cfg.blocks[block];
+        include.map(|include| include.range(block.start..block.end).next().is_some()).unwrap_or(true)
+    };
+
+    writeln!(out, "digraph rseq {{")?;
+    writeln!(out, "    node [shape=box, fontname=\"monospace\"];")?;
+    for (idx, block) in cfg.blocks.iter().enumerate().filter(|(idx, _)| included(*idx)) {
+        writeln!(out, "    b{} [label=\"{}\"];", idx, block_label(program, block.start, block.end))?;
+    }
+    for edge in cfg.edges.iter().filter(|edge| included(edge.from) && included(edge.to)) {
+        let mut attrs = match edge.kind {
+            EdgeKind::Next => vec![],
+            EdgeKind::Jump => vec!["label=\"jump\"".to_string()],
+            EdgeKind::Call => vec!["label=\"call\"".to_string(), "color=blue".to_string()],
+            EdgeKind::AfterCall => vec!["style=dotted".to_string()],
+            EdgeKind::Fork(track) => vec![format!("label=\"fork {}\"", track), "color=red".to_string()]
+        };
+        if edge.conditional {
+            attrs.push("style=dashed".into());
+        }
+        if attrs.is_empty() {
+            writeln!(out, "    b{} -> b{};", edge.from, edge.to)?;
+        } else {
+            writeln!(out, "    b{} -> b{} [{}];", edge.from, edge.to, attrs.join(", "))?;
+        }
+    }
+    writeln!(out, "}}")
+}
+
+/// Writes which tracks and subroutines call or fork which, as a Graphviz digraph.
+pub fn write_call_graph(out: &mut impl Write, program: &Program, map: &TrackMap) -> fmt::Result {
+    writeln!(out, "digraph calls {{")?;
+    for (idx, track) in map.tracks.iter().enumerate() {
+        writeln!(out, "    t{} [label=\"track {}\\n{}\", shape=box];", idx, track.number, escape(&program.name(track.entry)))?;
+    }
+    for &entry in map.subroutines.keys() {
+        writeln!(out, "    s{} [label=\"{}\"];", entry, escape(&program.name(entry)))?;
+    }
+
+    // The track that a fork in `song` starts.
+    let forked = |song: usize, number: u8, target: usize|
+        map.tracks.iter().position(|t| t.song == song && t.number == number && t.entry == target);
+    for (idx, track) in map.tracks.iter().enumerate() {
+        let mut calls = BTreeSet::new();
+        for flow in track.code.iter().flat_map(|&idx| program.successors(idx)) {
+            match flow {
+                Flow::Call { target, .. } => { calls.insert(target); },
+                Flow::Fork { track: number, target } => if let Some(other) = forked(track.song, number, target) {
+                    writeln!(out, "    t{} -> t{} [label=\"fork\", color=red];", idx, other)?;
+                },
+                _ => ()
+            }
+        }
+        for sub in calls {
+            writeln!(out, "    t{} -> s{};", idx, sub)?;
+        }
+    }
+    for sub in map.subroutines.values() {
+        for call in &sub.calls {
+            writeln!(out, "    s{} -> s{};", sub.entry, call)?;
+        }
+    }
+    writeln!(out, "}}")
+}
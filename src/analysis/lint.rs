@@ -0,0 +1,315 @@
+use super::{Program, Flow, tracks::TrackMap};
+use crate::instructions::{OptionalInst, Instruction, U8Parameters, U16Parameters, UserOp};
+
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+
+/// How many calls and loops can be open at once. Loops share the stack with calls.
+pub const CALL_STACK_DEPTH: usize = 3;
+/// How many variables there are; `_0` to `_47`.
+pub const VARIABLE_COUNT: u8 = 48;
+pub const TRACK_COUNT: u8 = crate::instructions::asm::TRACK_COUNT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error")
+        }
+    }
+}
+
+/// A problem found in a program, at the instruction with index `idx`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Lint {
+    pub idx: usize,
+    pub severity: Severity,
+    pub message: String
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Frame {
+    /// A call, and where it returns to.
+    Call(usize),
+    Loop
+}
+
+struct Linter<'p, 'a> {
+    program: &'p Program<'a>,
+    found: BTreeSet<Lint>,
+    /// The deepest the stack gets at each instruction.
+    depths: Vec<Option<usize>>
+}
+
+impl<'p, 'a> Linter<'p, 'a> {
+    fn report(&mut self, idx: usize, severity: Severity, message: impl Into<String>) {
+        self.found.insert(Lint { idx, severity, message: message.into() });
+    }
+
+    /// Runs through everything a track can do, keeping track of what's on the call stack.
+    fn check_track(&mut self, entry: usize) {
+        let program = self.program;
+        let mut seen = HashSet::new();
+        let mut todo = vec![(entry, Vec::new())];
+        while let Some((idx, stack)) = todo.pop() {
+            if !seen.insert((idx, stack.clone())) {
+                continue;
+            }
+            self.depths[idx] = Some(self.depths[idx].map_or(stack.len(), |depth| depth.max(stack.len())));
+            let inst = match &program.instructions[idx] {
+                OptionalInst::Byte(_) => {
+                    self.report(idx, Severity::Error, "A track runs into bytes that aren't instructions");
+                    continue;
+                },
+                OptionalInst::Label(_) => None,
+                OptionalInst::Instruction(inst) => Some(inst)
+            };
+            if program.falls_through(idx) && idx + 1 == program.len() {
+                self.report(idx, Severity::Error, "A track can run off the end without reaching end_track");
+            }
+
+            let mut after = stack.clone();
+            match inst.map(Instruction::unprefixed) {
+                Some(Instruction::LoopStart(_)) if stack.len() >= CALL_STACK_DEPTH =>
+                    self.report(idx, Severity::Error, format!("Loops and calls nest deeper than the limit of {}", CALL_STACK_DEPTH)),
+                Some(Instruction::LoopStart(_)) => after.push(Frame::Loop),
+                Some(Instruction::LoopEnd) => match stack.last() {
+                    Some(Frame::Loop) => { after.pop(); },
+                    Some(Frame::Call(_)) => self.report(idx, Severity::Error, "end_loop without a start_loop in the same subroutine"),
+                    None => self.report(idx, Severity::Error, "end_loop without a start_loop")
+                },
+                _ => ()
+            }
+
+            for flow in program.successors(idx) {
+                match flow {
+                    Flow::Next(next) | Flow::Jump(next) => todo.push((next, after.clone())),
+                    Flow::Call { ret, .. } if stack.len() >= CALL_STACK_DEPTH => {
+                        self.report(idx, Severity::Error, format!("Loops and calls nest deeper than the limit of {}", CALL_STACK_DEPTH));
+                        todo.push((ret, after.clone()));
+                    },
+                    Flow::Call { target, ret } => {
+                        let mut inner = after.clone();
+                        inner.push(Frame::Call(ret));
+                        todo.push((target, inner));
+                    },
+                    // Other tracks are checked on their own.
+                    Flow::Fork { .. } => (),
+                    Flow::Return => {
+                        // Returning unwinds any loops that are still open.
+                        let call = after.iter().rposition(|frame| matches!(frame, Frame::Call(_)));
+                        match call {
+                            Some(call) => {
+                                if call + 1 != after.len() {
+                                    self.report(idx, Severity::Warning, "ret inside a loop that's still open");
+                                }
+                                if let Frame::Call(ret) = after[call] {
+                                    todo.push((ret, after[..call].to_vec()));
+                                }
+                            },
+                            None => self.report(idx, Severity::Error, "ret with nothing on the call stack")
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_unreachable(&mut self, map: &TrackMap) {
+        let program = self.program;
+        let mut idx = 0;
+        while idx < program.len() {
+            if !map.owners[idx].is_empty() {
+                idx += 1;
+                continue;
+            }
+            let start = idx;
+            while idx < program.len() && map.owners[idx].is_empty() {
+                idx += 1;
+            }
+            let count = (start..idx).filter(|&idx| program.instruction(idx).is_some()).count();
+            if let Some(first) = (start..idx).find(|&idx| program.instruction(idx).is_some()) {
+                self.report(first, Severity::Warning, format!("Unreachable code ({} instruction{})", count, if count == 1 { "" } else { "s" }));
+            }
+        }
+    }
+
+    fn check_forks(&mut self, map: &TrackMap) {
+        let program = self.program;
+        for (song, _) in map.songs.iter().enumerate() {
+            let mut code = BTreeSet::new();
+            for track in map.tracks.iter().filter(|track| track.song == song) {
+                code.extend(&track.code);
+                for sub in &track.calls {
+                    code.extend(&map.subroutines[sub].code);
+                }
+            }
+            let masks: Vec<u16> = code.iter().filter_map(|&idx| match program.instruction(idx) {
+                Some(Instruction::SetU16Param { param: U16Parameters::TrackUsage, value }) => Some(*value),
+                _ => None
+            }).collect();
+            let mask = masks.iter().fold(0, |all, mask| all | mask);
+
+            for &idx in &code {
+                match program.instruction(idx) {
+                    Some(Instruction::Fork { track, .. }) if *track >= TRACK_COUNT =>
+                        self.report(idx, Severity::Error, format!("There are only {} tracks", TRACK_COUNT)),
+                    Some(Instruction::Fork { .. }) if masks.is_empty() =>
+                        self.report(idx, Severity::Warning, "fork in a song that never sets TrackUsage"),
+                    Some(Instruction::Fork { track, .. }) if mask & (1 << track) == 0 =>
+                        self.report(idx, Severity::Error, format!("fork to track {}, which TrackUsage doesn't include", track)),
+                    _ => ()
+                }
+            }
+        }
+    }
+
+    fn check_values(&mut self, idx: usize, inst: &Instruction) {
+        use U8Parameters::*;
+        let error = match *inst {
+            // Whatever the prefixed instruction has as its last argument is only a placeholder.
+            Instruction::Random { min, max, .. } => {
+                self.check_values(idx, &inst.with_last_arg(min));
+                self.check_values(idx, &inst.with_last_arg(max));
+                Some(format!("rand({}, {}) has its minimum above its maximum", min, max)).filter(|_| min > max)
+            },
+            Instruction::Variable { ref inst, var } => {
+                self.check_values(idx, &inst.with_last_arg(0));
+                Some(format!("Variable _{} doesn't exist, the last one is _{}", var, VARIABLE_COUNT - 1)).filter(|_| var >= VARIABLE_COUNT)
+            },
+            Instruction::Note { note, .. } if note > 127 => Some(format!("Note {} is above 127", note)),
+            Instruction::Note { velocity, .. } if velocity > 127 => Some(format!("Velocity {} is above 127", velocity)),
+            Instruction::PrintVar(var) | Instruction::UserProcess { var, .. } if var >= VARIABLE_COUNT && !matches!(inst, Instruction::UserProcess { op: UserOp::User, .. }) =>
+                Some(format!("Variable _{} doesn't exist, the last one is _{}", var, VARIABLE_COUNT - 1)),
+            Instruction::SetU8Param { param, value } => {
+                let max = match param {
+                    ModType => Some(2),
+                    Mute => Some(3),
+                    Monophonic | Polyphony | Tie | Portamento | Damper => Some(1),
+                    Pan | Volume | MasterVolume | BendRange | Priority | PortamentoCnt | ModDepth | ModSpeed | ModRange
+                        | Expression | Span | Cutoff | FxSendA | FxSendB | FxSendC | MainSend | InitPan => Some(127),
+                    _ => None
+                };
+                match param {
+                    Timebase if value == 0 => Some("Timebase can't be 0".to_string()),
+                    // -64 to 63
+                    Transpose if (value as i8) < -64 || (value as i8) > 63 => Some(format!("Transpose {} is outside -64 to 63", value as i8)),
+                    // -1 to 127
+                    EnvHold | Attack | Decay | Sustain | Release if value > 127 && value != 0xFF =>
+                        Some(format!("{:?} {} is outside -1 to 127", param, value)),
+                    _ => max.filter(|max| value > *max).map(|max| format!("{:?} {} is above {}", param, value, max))
+                }
+            },
+            _ => None
+        };
+        if let Some(message) = error {
+            self.report(idx, Severity::Error, message);
+        }
+    }
+}
+
+/// Checks a program for mistakes that would make it misbehave on hardware.
+/// The result is sorted by instruction index.
+pub fn lint(program: &Program) -> Vec<Lint> {
+    let map = TrackMap::new(program);
+    let mut linter = Linter { program, found: BTreeSet::new(), depths: vec![None; program.len()] };
+    linter.check_unreachable(&map);
+    linter.check_forks(&map);
+    for track in &map.tracks {
+        linter.check_track(track.entry);
+    }
+    for idx in 0..program.len() {
+        if let Some(inst) = program.instruction(idx) {
+            linter.check_values(idx, inst);
+        }
+    }
+    linter.found.into_iter().collect()
+}
+
+/// How many calls and loops are open at each instruction, at most, over every way the tracks run it,
+/// or `None` for instructions that nothing runs.
+pub fn stack_depths(program: &Program) -> Vec<Option<usize>> {
+    let map = TrackMap::new(program);
+    let mut linter = Linter { program, found: BTreeSet::new(), depths: vec![None; program.len()] };
+    for track in &map.tracks {
+        linter.check_track(track.entry);
+    }
+    linter.depths
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::assemble;
+    use std::path::Path;
+
+    fn lint_source(source: &str) -> Vec<(usize, Severity, String)> {
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        lint(&Program::new(&rseq.instructions)).into_iter().map(|lint| (lint.idx, lint.severity, lint.message)).collect()
+    }
+
+    #[test]
+    fn test_clean() {
+        let source = "
+            .track 0 at main
+            .track 1 at bass
+            main:
+                start_loop 2
+                call sub
+                end_loop
+                end_track
+            bass:
+                set Pan = 64
+                process _47 = 1
+                end_track
+            sub:
+                rest 48
+                ret
+        ";
+        assert_eq!(lint_source(source), []);
+    }
+
+    #[test]
+    fn test_problems() {
+        let source = "
+            fork 2, other
+            set Pan = 128
+            call a
+            ret
+            rest 1
+            a:
+                call b
+                ret
+            b:
+                start_loop 2
+                call c
+                end_loop
+                ret
+            c:
+                print _48
+                set Volume = rand(64, 200)
+                ret
+            other:
+                end_loop
+        ";
+        let found = lint_source(source);
+        let messages: Vec<&str> = found.iter().map(|(_, _, message)| message.as_str()).collect();
+        assert_eq!(messages, [
+            "fork in a song that never sets TrackUsage",
+            "Pan 128 is above 127",
+            "ret with nothing on the call stack",
+            "Unreachable code (1 instruction)",
+            "Loops and calls nest deeper than the limit of 3",
+            "Variable _48 doesn't exist, the last one is _47",
+            "Volume 200 is above 127",
+            "A track can run off the end without reaching end_track",
+            "end_loop without a start_loop",
+        ]);
+    }
+}
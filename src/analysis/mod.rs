@@ -0,0 +1,123 @@
+use crate::instructions::{OptionalInst, Instruction, Destination};
+
+use std::collections::HashMap;
+
+pub mod tracks;
+pub mod timing;
+pub mod cfg;
+pub mod dot;
+pub mod lint;
+pub mod diff;
+pub mod search;
+
+/// Where execution can go after an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Continues with the instruction at this index.
+    Next(usize),
+    Jump(usize),
+    /// Calls a subroutine, continuing at `ret` once it returns.
+    Call { target: usize, ret: usize },
+    /// Starts another track at `target`.
+    Fork { track: u8, target: usize },
+    /// Returns to whoever called the current subroutine.
+    Return
+}
+
+/// A list of instructions, indexed by label.
+pub struct Program<'a> {
+    pub instructions: &'a [OptionalInst],
+    labels: HashMap<&'a str, usize>
+}
+
+impl<'a> Program<'a> {
+    pub fn new(instructions: &'a [OptionalInst]) -> Program<'a> {
+        let labels = instructions.iter().enumerate().filter_map(|(idx, inst)| match inst {
+            OptionalInst::Label(name) => Some((name.as_str(), idx)),
+            _ => None
+        }).collect();
+        Program { instructions, labels }
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels.get(name).cloned()
+    }
+
+    pub fn target(&self, dest: &Destination) -> Option<usize> {
+        match dest {
+            Destination::Label(name) => self.label(name)
+        }
+    }
+
+    pub fn instruction(&self, idx: usize) -> Option<&'a Instruction> {
+        match self.instructions.get(idx) {
+            Some(OptionalInst::Instruction(i)) => Some(i),
+            _ => None
+        }
+    }
+
+    /// The label at `idx`, if there is one.
+    pub fn label_at(&self, idx: usize) -> Option<&'a str> {
+        match self.instructions.get(idx) {
+            Some(OptionalInst::Label(name)) => Some(name),
+            _ => None
+        }
+    }
+
+    /// A name for `idx` to show to people: its label if it has one, otherwise its index.
+    pub fn name(&self, idx: usize) -> String {
+        self.label_at(idx).map(String::from).unwrap_or_else(|| format!("@{}", idx))
+    }
+
+    /// Whether the instruction at `idx` only runs if the preceding `?` passes.
+    pub fn is_guarded(&self, idx: usize) -> bool {
+        idx > 0 && self.instruction(idx - 1) == Some(&Instruction::If)
+    }
+
+    /// Whether the instruction at `idx` can continue with the next one in the list.
+    /// Bytes count as falling through, so that they stay next to whatever is around them.
+    pub fn falls_through(&self, idx: usize) -> bool {
+        match self.instruction(idx) {
+            Some(Instruction::Jump(_)) | Some(Instruction::Return) | Some(Instruction::EndOfTrack) => self.is_guarded(idx),
+            _ => true
+        }
+    }
+
+    /// Everywhere execution can go after the instruction at `idx`.
+    /// Destinations that don't resolve to a label and running off the end of the list are left out.
+    pub fn successors(&self, idx: usize) -> Vec<Flow> {
+        let next = idx + 1;
+        let mut flow = Vec::new();
+        match &self.instructions[idx] {
+            OptionalInst::Byte(_) => return flow,
+            OptionalInst::Label(_) => flow.push(Flow::Next(next)),
+            OptionalInst::Instruction(inst) => match inst {
+                Instruction::Jump(dest) => flow.extend(self.target(dest).map(Flow::Jump)),
+                Instruction::Call(dest) => flow.extend(self.target(dest).map(|target| Flow::Call { target, ret: next })),
+                Instruction::Fork { track, dest } => {
+                    flow.extend(self.target(dest).map(|target| Flow::Fork { track: *track, target }));
+                    flow.push(Flow::Next(next));
+                },
+                Instruction::Return => flow.push(Flow::Return),
+                Instruction::EndOfTrack => (),
+                _ => flow.push(Flow::Next(next))
+            }
+        }
+        if self.is_guarded(idx) && !flow.contains(&Flow::Next(next)) {
+            flow.push(Flow::Next(next));
+        }
+        flow.retain(|f| match f {
+            Flow::Next(next) | Flow::Call { ret: next, .. } => *next < self.len(),
+            _ => true
+        });
+        flow
+    }
+}
@@ -0,0 +1,140 @@
+use super::Program;
+use crate::instructions::{OptionalInst, Instruction};
+
+use std::str::FromStr;
+
+/// An instruction in assembler syntax, where `*` in any word matches any run of characters.
+/// Words are separated by spaces and commas, and each one in the pattern has to match a word
+/// of the instruction, so `set Pan = *` matches every `set Pan` and `process _* rand *` every `rand`.
+/// Anything in parentheses is one word, with its spaces left out, so `rand(24,*)` matches `rand(24, 48)`.
+/// Starting with `?` only matches instructions that a `?` guards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    guarded: bool,
+    words: Vec<String>
+}
+
+/// Splits `text` into words, keeping anything in parentheses together as one word without its spaces.
+fn words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut depth = 0usize;
+    for c in text.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            c if c.is_whitespace() && depth > 0 => continue,
+            c if c.is_whitespace() || (c == ',' && depth == 0) => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                continue;
+            },
+            _ => ()
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters.
+fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false
+    };
+    let parts: Vec<&str> = parts.collect();
+    match parts.split_last() {
+        // No `*` at all.
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(found) => rest = &rest[found + part.len()..],
+                    None => return false
+                }
+            }
+            rest.len() >= last.len() && rest.ends_with(last)
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (guarded, s) = match s.strip_prefix('?') {
+            Some(rest) => (true, rest),
+            None => (false, s)
+        };
+        let words = words(s);
+        if words.is_empty() {
+            return Err("Empty pattern".into());
+        }
+        Ok(Pattern { guarded, words })
+    }
+}
+
+impl Pattern {
+    /// Whether the pattern matches `inst`, ignoring whether it's guarded. Labels never match.
+    pub fn matches(&self, inst: &OptionalInst) -> bool {
+        if let OptionalInst::Label(_) = inst {
+            return false;
+        }
+        let text = inst.to_string();
+        let words = words(&text);
+        words.len() == self.words.len() && self.words.iter().zip(&words).all(|(pattern, word)| glob(pattern, word))
+    }
+
+    /// Indices of every instruction in `program` that the pattern matches.
+    pub fn find(&self, program: &Program) -> Vec<usize> {
+        (0..program.len())
+            .filter(|&idx| !self.guarded || program.is_guarded(idx))
+            .filter(|&idx| program.instruction(idx) != Some(&Instruction::If))
+            .filter(|&idx| self.matches(&program.instructions[idx]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::assemble;
+    use std::path::Path;
+
+    #[test]
+    fn test_search() {
+        let source = "
+            main:
+                set Pan = 64
+                set InitPan = 64
+                ? process _3 rand 10
+                process 0x10
+                fork 15, main
+                .bytes 0xe5
+                note 60, 100, rand(24, 48)
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let find = |pattern: &str| pattern.parse::<Pattern>().unwrap().find(&program);
+
+        assert_eq!(find("set Pan = *"), [1]);
+        assert_eq!(find("set *Pan = 6*"), [1, 2]);
+        assert_eq!(find("process _* rand *"), [4]);
+        assert!(find("? process *").is_empty());
+        assert_eq!(find("? process * * *"), [4]);
+        assert_eq!(find("process 0x*"), [5]);
+        assert_eq!(find("fork 15,*"), [6]);
+        assert_eq!(find(".byte 0xe5"), [7]);
+        assert_eq!(find("note * * rand(24,*)"), [8]);
+        assert_eq!(find("note 60, 100, rand( 24, 48 )"), [8]);
+        assert!(find("note * * * 48)").is_empty());
+        assert!("".parse::<Pattern>().is_err());
+    }
+}
@@ -0,0 +1,527 @@
+use super::{Program, tracks::TrackMap};
+use crate::instructions::{Instruction, U8Parameters, U16Parameters, UserOp};
+use super::lint::VARIABLE_COUNT;
+
+use std::collections::HashMap;
+
+/// Ticks per quarter note until a track sets its own `Timebase`.
+pub const DEFAULT_TIMEBASE: u8 = 48;
+/// Beats per minute until something sets `Tempo`.
+pub const DEFAULT_TEMPO: u16 = 120;
+pub const BEATS_PER_BAR: u64 = 4;
+/// How many instructions to run a track for before giving up on finding where it loops.
+pub const MAX_STEPS: usize = 1_000_000;
+
+/// How long the instruction waits before the track carries on, if at all.
+/// Notes only wait while note wait is on, which it is until `Polyphony` (0xC7, really note wait) is set to 0.
+fn wait(inst: &Instruction, note_wait: bool) -> Option<u64> {
+    match inst {
+        Instruction::Rest(len) => Some(*len),
+        Instruction::Note { len, .. } if note_wait => Some(*len),
+        _ => None
+    }
+}
+
+/// A point in time, in ticks of the given timebase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub tick: u64,
+    pub timebase: u8
+}
+
+impl Position {
+    /// The 1-based bar and beat, and how many ticks into the beat, assuming 4/4.
+    pub fn bar_beat(&self) -> (u64, u64, u64) {
+        let timebase = u64::from(self.timebase.max(1));
+        let beat = self.tick / timebase;
+        (beat / BEATS_PER_BAR + 1, beat % BEATS_PER_BAR + 1, self.tick % timebase)
+    }
+}
+
+/// Counts `rest`s (and notes, with note wait on) through each run of straight-line code, giving the position of every instruction
+/// relative to the start of its run. A run starts at the beginning of the list and after anything
+/// that doesn't fall through, like a `jump` or `end_track`.
+///
+/// Loops are counted as many times as they run. After a `call`, an infinite loop, or a wait that's
+/// guarded or takes its length from a prefix, the position isn't known, so it's `None` until the next run starts.
+pub fn linear_positions(program: &Program) -> Vec<Option<Position>> {
+    let mut positions = Vec::with_capacity(program.len());
+    let mut tick = Some(0);
+    let mut timebase = DEFAULT_TIMEBASE;
+    let mut note_wait = true;
+    let mut loops: Vec<(Option<u64>, u8)> = Vec::new();
+
+    for idx in 0..program.len() {
+        if idx > 0 && !program.falls_through(idx - 1) {
+            tick = Some(0);
+            loops.clear();
+        }
+        positions.push(tick.map(|tick| Position { tick, timebase }));
+
+        let guarded = program.is_guarded(idx);
+        let inst = match program.instruction(idx) {
+            Some(inst) => inst,
+            None => continue
+        };
+        let prefixed = inst.unprefixed() != inst;
+        if let Some(len) = wait(inst.unprefixed(), note_wait) {
+            tick = if guarded || prefixed { None } else { tick.map(|tick| tick + len) };
+        }
+        match inst {
+            Instruction::Call(_) => tick = None,
+            Instruction::LoopStart(count) => loops.push((tick, *count)),
+            // How many times it runs isn't known, which works out the same as forever.
+            Instruction::Random { inst, .. } | Instruction::Variable { inst, .. } if matches!(**inst, Instruction::LoopStart(_)) =>
+                loops.push((tick, 0)),
+            Instruction::LoopEnd => {
+                let (start, count) = loops.pop().unwrap_or((None, 0));
+                tick = match (start, tick, count) {
+                    (_, _, 0) => None,
+                    (Some(start), Some(end), count) => Some(start + (end - start) * u64::from(count)),
+                    _ => None
+                };
+            },
+            Instruction::SetU8Param { param: U8Parameters::Timebase, value } => timebase = *value,
+            Instruction::SetU8Param { param: U8Parameters::Polyphony, value } => note_wait = *value != 0,
+            _ => ()
+        }
+    }
+    positions
+}
+
+/// A length of time, in ticks and in seconds under whatever tempo was in effect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Length {
+    pub ticks: u64,
+    pub seconds: f64
+}
+
+/// What a track does once it's done with its intro.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum End {
+    /// It reaches `end_track`, and the intro is all of it.
+    Ends,
+    /// It goes back to the instruction at `point` forever, taking `length` each time round.
+    Loops { point: usize, length: Length },
+    /// It was still going after `MAX_STEPS` instructions without repeating itself.
+    Unknown
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackTiming {
+    /// Index into `TrackMap::tracks`.
+    pub track: usize,
+    /// When the track gets forked, in ticks from the start of the song.
+    pub start: u64,
+    /// How long the track runs before it loops or ends.
+    pub intro: Length,
+    pub end: End,
+    /// Whether the track does things that depend on random numbers or user callbacks,
+    /// which are assumed to be 0 here (or the least a `rand(...)` can be), so the real timing might be different.
+    pub uncertain: bool
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SongTiming {
+    pub tracks: Vec<TrackTiming>,
+    /// Tracks that don't stay in step with track 0, with an explanation for each.
+    pub drift: Vec<(usize, String)>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Frame {
+    Call(usize),
+    /// Where the loop body starts, and how many more times it runs (0 for forever).
+    Loop { body: usize, count: u8 }
+}
+
+/// Everything that decides what a track does next.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct State {
+    idx: usize,
+    stack: Vec<Frame>,
+    vars: Vec<i16>,
+    flag: bool,
+    note_wait: bool
+}
+
+/// Changes to `Tempo` and `Timebase`, which affect every track in the song.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Tempo(u16),
+    Timebase(u8)
+}
+
+pub(crate) struct Run {
+    /// Ticks spent before the loop point was first reached (or in total, if it ends).
+    intro: u64,
+    /// Where the track loops to and how long each time round takes, in ticks.
+    pub(crate) looped: Option<(usize, u64)>,
+    /// How many instructions had been run when the loop point was first reached.
+    pub(crate) loop_steps: usize,
+    pub(crate) finished: bool,
+    uncertain: bool,
+    changes: Vec<(u64, Change)>,
+    forks: Vec<(u64, u8, usize)>
+}
+
+fn user_process(state: &mut State, op: UserOp, var: u8, imm: i16) -> bool {
+    let var = match state.vars.get_mut(var as usize) {
+        Some(var) => var,
+        None => return true
+    };
+    match op {
+        UserOp::Set => *var = imm,
+        UserOp::Add => *var = var.wrapping_add(imm),
+        UserOp::Sub => *var = var.wrapping_sub(imm),
+        UserOp::Mul => *var = var.wrapping_mul(imm),
+        UserOp::Div => *var = if imm == 0 { *var } else { var.wrapping_div(imm) },
+        UserOp::Shift if imm < 0 => *var = var.checked_shr(imm.unsigned_abs().into()).unwrap_or(0),
+        UserOp::Shift => *var = var.checked_shl(imm as u32).unwrap_or(0),
+        UserOp::And => *var &= imm,
+        UserOp::Or => *var |= imm,
+        UserOp::Xor => *var ^= imm,
+        UserOp::Not => *var = !*var,
+        UserOp::Mod => *var = if imm == 0 { *var } else { var.wrapping_rem(imm) },
+        UserOp::CmpEq => state.flag = *var == imm,
+        UserOp::CmpGe => state.flag = *var >= imm,
+        UserOp::CmpGt => state.flag = *var > imm,
+        UserOp::CmpLe => state.flag = *var <= imm,
+        UserOp::CmpLt => state.flag = *var < imm,
+        UserOp::CmpNe => state.flag = *var != imm,
+        UserOp::Rand | UserOp::User => {
+            *var = 0;
+            return true;
+        }
+    }
+    false
+}
+
+/// Runs one track from `entry` until it ends or gets back to somewhere it's been in exactly the same state,
+/// starting with the variables in `vars`. `visit` is given every instruction that runs, in order, with the
+/// tick it runs at and its prefix resolved.
+pub(crate) fn run(program: &Program, entry: usize, vars: &[i16], mut visit: impl FnMut(usize, u64, &Instruction)) -> Run {
+    let mut state = State { idx: entry, stack: Vec::new(), vars: vars.to_vec(), flag: false, note_wait: true };
+    let mut result = Run { intro: 0, looped: None, loop_steps: 0, finished: false, uncertain: false, changes: Vec::new(), forks: Vec::new() };
+    // The state at each label and loop start we've been to, and the tick and step we were at.
+    let mut seen: HashMap<State, (u64, usize)> = HashMap::new();
+    let mut tick = 0;
+    let mut steps = 0;
+    let mut skip = false;
+
+    for _ in 0..MAX_STEPS {
+        let idx = state.idx;
+        if idx >= program.len() {
+            result.finished = true;
+            break;
+        }
+        if program.label_at(idx).is_some() || matches!(state.stack.last(), Some(Frame::Loop { body, .. }) if *body == idx) {
+            if let Some(&(before, step)) = seen.get(&state) {
+                result.intro = before;
+                result.looped = Some((idx, tick - before));
+                result.loop_steps = step;
+                return result;
+            }
+            seen.insert(state.clone(), (tick, steps));
+        }
+
+        state.idx += 1;
+        let inst = match program.instruction(idx) {
+            Some(inst) => inst,
+            None if program.label_at(idx).is_some() => continue,
+            // A track that runs into data is as good as ended.
+            None => {
+                result.finished = true;
+                break;
+            }
+        };
+        if std::mem::replace(&mut skip, false) {
+            continue;
+        }
+
+        // Prefixes are resolved first, with random numbers taken to be as small as they can be.
+        let resolved;
+        let inst = match inst {
+            Instruction::Random { min, .. } => {
+                result.uncertain = true;
+                resolved = inst.with_last_arg(*min);
+                &resolved
+            },
+            Instruction::Variable { var, .. } => {
+                let value = state.vars.get(*var as usize).copied();
+                result.uncertain |= value.is_none();
+                resolved = inst.with_last_arg(value.unwrap_or(0));
+                &resolved
+            },
+            inst => inst
+        };
+        visit(idx, tick, inst);
+        steps += 1;
+        tick += wait(inst, state.note_wait).unwrap_or(0);
+        match inst {
+            Instruction::If => skip = !state.flag,
+            Instruction::Fork { track, dest } => result.forks.extend(program.target(dest).map(|target| (tick, *track, target))),
+            Instruction::Jump(dest) => match program.target(dest) {
+                Some(target) => state.idx = target,
+                None => {
+                    result.finished = true;
+                    break;
+                }
+            },
+            Instruction::Call(dest) => match program.target(dest) {
+                Some(target) => {
+                    state.stack.push(Frame::Call(state.idx));
+                    state.idx = target;
+                },
+                None => {
+                    result.finished = true;
+                    break;
+                }
+            },
+            Instruction::Return => {
+                // Returning unwinds any loops that are still open.
+                while let Some(Frame::Loop { .. }) = state.stack.last() {
+                    state.stack.pop();
+                }
+                match state.stack.pop() {
+                    Some(Frame::Call(ret)) => state.idx = ret,
+                    _ => {
+                        result.finished = true;
+                        break;
+                    }
+                }
+            },
+            Instruction::LoopStart(count) => state.stack.push(Frame::Loop { body: state.idx, count: *count }),
+            Instruction::LoopEnd => match state.stack.last_mut() {
+                Some(Frame::Loop { body, count: 0 }) => state.idx = *body,
+                Some(Frame::Loop { body, count }) => {
+                    *count -= 1;
+                    if *count == 0 {
+                        state.stack.pop();
+                    } else {
+                        state.idx = *body;
+                    }
+                },
+                _ => ()
+            },
+            Instruction::UserProcess { op, var, imm } => result.uncertain |= user_process(&mut state, *op, *var, *imm),
+            Instruction::SetU8Param { param: U8Parameters::Polyphony, value } => state.note_wait = *value != 0,
+            Instruction::SetU8Param { param: U8Parameters::Timebase, value } => result.changes.push((tick, Change::Timebase(*value))),
+            Instruction::SetU16Param { param: U16Parameters::Tempo, value } => result.changes.push((tick, Change::Tempo(*value))),
+            Instruction::EndOfTrack => {
+                result.finished = true;
+                break;
+            },
+            _ => ()
+        }
+    }
+    result.intro = tick;
+    result
+}
+
+/// Runs every track of `song` from the tick it's forked at, giving the index of each in `map.tracks`, where it
+/// starts and how it ran. `visit` is given every instruction that runs, with the song-wide tick it runs at.
+fn run_song(program: &Program, map: &TrackMap, song: usize, mut visit: impl FnMut(usize, u64, &Instruction)) -> Vec<(usize, u64, Run)> {
+    let mut runs = Vec::new();
+    let mut starts: HashMap<(u8, usize), u64> = HashMap::new();
+    for (idx, track) in map.tracks.iter().enumerate().filter(|(_, track)| track.song == song) {
+        let start = starts.get(&(track.number, track.entry)).cloned().unwrap_or(0);
+        let run = run(program, track.entry, &[0; VARIABLE_COUNT as usize], |idx, tick, inst| visit(idx, start + tick, inst));
+        for &(tick, number, target) in &run.forks {
+            starts.entry((number, target)).or_insert(start + tick);
+        }
+        runs.push((idx, start, run));
+    }
+    runs
+}
+
+/// Turns ticks into seconds, following the changes to tempo and timebase.
+pub(crate) struct TempoMap {
+    changes: Vec<(u64, Change)>
+}
+
+impl TempoMap {
+    /// The changes every track of a song makes, song-wide.
+    fn new(runs: &[(usize, u64, Run)]) -> TempoMap {
+        let mut changes: Vec<(u64, Change)> = runs.iter().flat_map(|(_, start, run)| run.changes.iter().map(move |&(tick, change)| (start + tick, change))).collect();
+        changes.sort_by_key(|(tick, _)| *tick);
+        TempoMap { changes }
+    }
+
+    /// The tempo once everything up to and including `tick` has run.
+    pub(crate) fn tempo_at(&self, tick: u64) -> u16 {
+        self.tempos().take_while(|&(at, _)| at <= tick).last().map_or(DEFAULT_TEMPO, |(_, tempo)| tempo)
+    }
+
+    /// Every change to the tempo, in order, with the tick it happens at.
+    pub(crate) fn tempos(&self) -> impl Iterator<Item = (u64, u16)> + '_ {
+        self.changes.iter().filter_map(|&(tick, change)| match change {
+            Change::Tempo(tempo) => Some((tick, tempo)),
+            Change::Timebase(_) => None
+        })
+    }
+
+    fn seconds(&self, from: u64, to: u64) -> f64 {
+        let (mut tempo, mut timebase) = (DEFAULT_TEMPO, DEFAULT_TIMEBASE);
+        let mut seconds = 0.0;
+        let mut last = from;
+        let mut span = |start: u64, end: u64, tempo: u16, timebase: u8| if end > start {
+            seconds += (end - start) as f64 / f64::from(timebase.max(1)) * 60.0 / f64::from(tempo.max(1));
+        };
+        for &(tick, change) in &self.changes {
+            if tick >= to {
+                break;
+            }
+            if tick > last {
+                span(last, tick, tempo, timebase);
+                last = tick;
+            }
+            match change {
+                Change::Tempo(new) => tempo = new,
+                Change::Timebase(new) => timebase = new
+            }
+        }
+        span(last.max(from), to, tempo, timebase);
+        seconds
+    }
+
+    fn length(&self, start: u64, ticks: u64) -> Length {
+        Length { ticks, seconds: self.seconds(start, start + ticks) }
+    }
+}
+
+/// Works out how long each track of a song runs before it loops or ends, and where it loops to.
+///
+/// Each track is run on its own, following calls, loops and `?`s as it goes; variables start at 0,
+/// and random numbers and user callbacks are taken to be 0 as well. Tempo and timebase changes from
+/// every track apply to the whole song, and seconds count them in for as far as each track was run.
+pub fn song_timing(program: &Program, map: &TrackMap, song: usize) -> SongTiming {
+    let runs = run_song(program, map, song, |_, _, _| ());
+    let tempo = TempoMap::new(&runs);
+
+    let tracks: Vec<TrackTiming> = runs.into_iter().map(|(track, start, run)| TrackTiming {
+        track,
+        start,
+        intro: tempo.length(start, run.intro),
+        end: match run.looped {
+            Some((point, ticks)) => End::Loops { point, length: tempo.length(start + run.intro, ticks) },
+            None if run.finished => End::Ends,
+            None => End::Unknown
+        },
+        uncertain: run.uncertain
+    }).collect();
+
+    let mut drift = Vec::new();
+    if let Some(first) = tracks.first() {
+        for other in &tracks[1..] {
+            let problem = match (first.end, other.end) {
+                (End::Loops { length: main, .. }, End::Loops { length, .. }) if length.ticks == 0 || main.ticks % length.ticks != 0 =>
+                    Some(format!("Its loop of {} ticks doesn't fit evenly into track 0's loop of {} ticks", length.ticks, main.ticks)),
+                (End::Ends, End::Loops { .. }) => Some("It loops forever, but track 0 ends".to_string()),
+                (End::Ends, End::Ends) if other.start + other.intro.ticks > first.intro.ticks =>
+                    Some(format!("It ends at tick {}, after track 0 ends at tick {}", other.start + other.intro.ticks, first.intro.ticks)),
+                _ => None
+            };
+            drift.extend(problem.map(|problem| (other.track, problem)));
+        }
+    }
+    SongTiming { tracks, drift }
+}
+
+/// Every song-wide tick each instruction runs at in `song`, in the order it gets there, which is none at all
+/// if no track of it runs the instruction, along with how the tempo changes. Tracks are run the same way as
+/// in `song_timing`.
+pub(crate) fn song_timeline(program: &Program, map: &TrackMap, song: usize) -> (Vec<Vec<u64>>, TempoMap) {
+    let mut ticks = vec![Vec::new(); program.len()];
+    let runs = run_song(program, map, song, |idx, tick, _| ticks[idx].push(tick));
+    (ticks, TempoMap::new(&runs))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::assemble;
+    use std::path::Path;
+
+    #[test]
+    fn test_linear_positions() {
+        let source = "
+            rest 96
+            start_loop 3
+            rest 48
+            end_loop
+            set Timebase = 96
+            note 60, 100, 1000
+            call sub
+            rest 1
+            jump end
+            sub:
+            rest 200
+            ret
+            end:
+            end_track
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let positions: Vec<Option<u64>> = linear_positions(&Program::new(&rseq.instructions)).iter().map(|p| p.map(|p| p.tick)).collect();
+        assert_eq!(positions, [
+            Some(0), Some(96), Some(96), Some(144), Some(240), Some(240),
+            Some(1240), None, None,
+            Some(0), Some(0), Some(200),
+            Some(0), Some(0)
+        ]);
+        assert_eq!(Position { tick: 240, timebase: 48 }.bar_beat(), (2, 2, 0));
+        assert_eq!(Position { tick: 250, timebase: 96 }.bar_beat(), (1, 3, 58));
+    }
+
+    #[test]
+    fn test_song_timing() {
+        let source = "
+            .track 0 at main
+            .track 1 at bass
+            .track 2 at drums
+            main:
+                set Tempo = 60
+                rest 96
+            main_loop:
+                call bar
+                call bar
+                jump main_loop
+            bar:
+                note 60, 100, 48
+                rest 48
+                ret
+            bass:
+                process _0 = 0
+            bass_loop:
+                process _0 += 1
+                process _0 == 3
+                ? jump bass_end
+                rest 96
+                jump bass_loop
+            bass_end:
+                end_track
+            drums:
+                start_loop 0
+                rest 40
+                end_loop
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let map = TrackMap::new(&program);
+        let timing = song_timing(&program, &map, 0);
+
+        let main = &timing.tracks[0];
+        assert_eq!((main.intro.ticks, main.intro.seconds), (96, 2.0));
+        match main.end {
+            End::Loops { point, length } => {
+                assert_eq!(program.name(point), "main_loop");
+                assert_eq!((length.ticks, length.seconds), (192, 4.0));
+            },
+            other => panic!("{:?}", other)
+        }
+        assert_eq!(timing.tracks[1].intro.ticks, 192);
+        assert_eq!(timing.tracks[1].end, End::Ends);
+        assert!(matches!(timing.tracks[2].end, End::Loops { length: Length { ticks: 40, .. }, .. }));
+        assert_eq!(timing.drift.iter().map(|(track, _)| *track).collect::<Vec<_>>(), [2]);
+    }
+}
@@ -0,0 +1,245 @@
+use super::{Program, Flow};
+use crate::instructions::OptionalInst;
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+/// One track of a song, as started by a `fork` (or the song itself, for track 0).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    /// Index into `TrackMap::songs`.
+    pub song: usize,
+    pub number: u8,
+    pub entry: usize,
+    /// Instructions the track runs outside of subroutines.
+    pub code: BTreeSet<usize>,
+    /// Entries of every subroutine the track calls, including through other subroutines.
+    pub calls: BTreeSet<usize>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subroutine {
+    pub entry: usize,
+    /// Instructions the subroutine runs, not counting the subroutines it calls itself.
+    pub code: BTreeSet<usize>,
+    /// Entries of the subroutines it calls directly.
+    pub calls: BTreeSet<usize>,
+    forks: Vec<(u8, usize)>
+}
+
+/// Which part of a split listing an instruction goes in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Section {
+    Track(usize),
+    Subroutine(usize),
+    /// Anything no track reaches, such as data.
+    Unreached
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackMap {
+    /// The entry of each song: index 0, and every label that nothing jumps to and no other song reaches.
+    pub songs: Vec<usize>,
+    pub tracks: Vec<Track>,
+    pub subroutines: BTreeMap<usize, Subroutine>,
+    /// For each instruction, the indices of the tracks that run it, directly or in a subroutine.
+    pub owners: Vec<Vec<usize>>
+}
+
+/// Follows everything reachable from `entry` without going into subroutines,
+/// returning the instructions reached, the subroutines called and the tracks forked.
+fn walk(program: &Program, entry: usize) -> (BTreeSet<usize>, BTreeSet<usize>, Vec<(u8, usize)>) {
+    let mut code = BTreeSet::new();
+    let mut calls = BTreeSet::new();
+    let mut forks = Vec::new();
+    let mut todo = vec![entry];
+    while let Some(idx) = todo.pop() {
+        if !code.insert(idx) {
+            continue;
+        }
+        for flow in program.successors(idx) {
+            match flow {
+                Flow::Next(next) | Flow::Jump(next) => todo.push(next),
+                Flow::Call { target, ret } => {
+                    calls.insert(target);
+                    todo.push(ret);
+                },
+                Flow::Fork { track, target } => forks.push((track, target)),
+                Flow::Return => ()
+            }
+        }
+    }
+    (code, calls, forks)
+}
+
+impl TrackMap {
+    pub fn new(program: &Program) -> TrackMap {
+        let mut map = TrackMap { songs: Vec::new(), tracks: Vec::new(), subroutines: BTreeMap::new(), owners: vec![Vec::new(); program.len()] };
+
+        let targets: HashSet<usize> = (0..program.len()).flat_map(|idx| program.successors(idx)).filter_map(|flow| match flow {
+            Flow::Jump(target) | Flow::Call { target, .. } | Flow::Fork { target, .. } => Some(target),
+            _ => None
+        }).collect();
+        let roots = std::iter::once(0).chain((0..program.len()).filter(|&idx| program.label_at(idx).is_some() && !targets.contains(&idx)));
+
+        let mut covered = HashSet::new();
+        for root in roots {
+            match program.instructions.get(root) {
+                None | Some(OptionalInst::Byte(_)) => continue,
+                _ if covered.contains(&root) => continue,
+                _ => ()
+            }
+            let song = map.songs.len();
+            map.songs.push(root);
+
+            let mut started = HashSet::new();
+            let mut todo = vec![(0, root)];
+            while let Some((number, entry)) = todo.pop() {
+                if !started.insert((number, entry)) {
+                    continue;
+                }
+                let (code, direct, mut forks) = walk(program, entry);
+                let calls = map.called(program, &direct);
+                for sub in &calls {
+                    forks.extend(&map.subroutines[sub].forks);
+                }
+                // Keep the tracks in the order they're forked.
+                todo.extend(forks.into_iter().rev());
+
+                let track = map.tracks.len();
+                let subroutines = &map.subroutines;
+                let reached: Vec<usize> = code.iter().chain(calls.iter().flat_map(|sub| &subroutines[sub].code)).cloned().collect();
+                for idx in reached {
+                    if !map.owners[idx].contains(&track) {
+                        map.owners[idx].push(track);
+                    }
+                    covered.insert(idx);
+                }
+                map.tracks.push(Track { song, number, entry, code, calls });
+            }
+        }
+        map
+    }
+
+    /// Walks every subroutine reachable from `direct`, returning all of their entries.
+    fn called(&mut self, program: &Program, direct: &BTreeSet<usize>) -> BTreeSet<usize> {
+        let mut calls = BTreeSet::new();
+        let mut todo: Vec<usize> = direct.iter().cloned().collect();
+        while let Some(entry) = todo.pop() {
+            if !calls.insert(entry) {
+                continue;
+            }
+            let sub = self.subroutines.entry(entry).or_insert_with(|| {
+                let (code, calls, forks) = walk(program, entry);
+                Subroutine { entry, code, calls, forks }
+            });
+            todo.extend(&sub.calls);
+        }
+        calls
+    }
+
+    /// The other tracks that run some of the same instructions as `track`, and how many they share.
+    pub fn shared_with(&self, track: usize) -> BTreeMap<usize, usize> {
+        let mut shared = BTreeMap::new();
+        let code = &self.tracks[track].code;
+        for &idx in code {
+            for &other in self.owners[idx].iter().filter(|&&other| other != track) {
+                *shared.entry(other).or_insert(0) += 1;
+            }
+        }
+        shared
+    }
+
+    /// The tracks that call the subroutine at `entry`.
+    pub fn callers(&self, entry: usize) -> Vec<usize> {
+        (0..self.tracks.len()).filter(|&track| self.tracks[track].calls.contains(&entry)).collect()
+    }
+
+    /// Divides every instruction between the tracks and subroutines, so that each one is listed once.
+    ///
+    /// Runs of instructions that fall through into each other are kept together, and go in the
+    /// first track that runs any of them, or the first subroutine if no track runs them outside of one.
+    /// Within a section the instructions stay in their original order, so writing out the sections
+    /// one after another gives a program that behaves the same.
+    pub fn sections(&self, program: &Program) -> Vec<(Section, Vec<usize>)> {
+        let mut sections: BTreeMap<Section, Vec<usize>> = BTreeMap::new();
+        for track in 0..self.tracks.len() {
+            sections.insert(Section::Track(track), Vec::new());
+        }
+        for &entry in self.subroutines.keys() {
+            sections.insert(Section::Subroutine(entry), Vec::new());
+        }
+
+        let mut start = 0;
+        while start < program.len() {
+            let mut end = start;
+            while end + 1 < program.len() && program.falls_through(end) {
+                end += 1;
+            }
+            let chain = start..=end;
+            let track = (0..self.tracks.len()).find(|&track| chain.clone().any(|idx| self.tracks[track].code.contains(&idx)));
+            let sub = self.subroutines.values().find(|sub| chain.clone().any(|idx| sub.code.contains(&idx)));
+            let section = match (track, sub) {
+                (Some(track), _) => Section::Track(track),
+                (None, Some(sub)) => Section::Subroutine(sub.entry),
+                (None, None) => Section::Unreached
+            };
+            sections.entry(section).or_default().extend(chain);
+            start = end + 1;
+        }
+        sections.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::assemble;
+    use std::path::Path;
+
+    #[test]
+    fn test_tracks() {
+        let source = "
+            .track 1 at bass
+            .track 2 at drums
+            seq:
+                call intro
+            loop:
+                note 60, 100, 48
+                jump loop
+            bass:
+                call intro
+                jump loop
+            drums:
+                ? jump drums_end
+                call fill
+            drums_end:
+                end_track
+            intro:
+                call fill
+                ret
+            fill:
+                rest 48
+                ret
+            .bytes 1, 2
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let map = TrackMap::new(&program);
+
+        assert_eq!(map.songs, [0]);
+        let tracks: Vec<(u8, String)> = map.tracks.iter().map(|t| (t.number, program.name(t.entry))).collect();
+        assert_eq!(tracks, [(0, "@0".into()), (1, "bass".into()), (2, "drums".into())]);
+
+        let names = |set: &BTreeSet<usize>| set.iter().map(|&idx| program.name(idx)).collect::<Vec<_>>();
+        assert_eq!(names(&map.tracks[0].calls), ["intro", "fill"]);
+        assert_eq!(names(&map.tracks[2].calls), ["fill"]);
+        assert_eq!(map.shared_with(1), [(0, 3)].iter().cloned().collect());
+        assert_eq!(map.callers(program.label("fill").unwrap()), [0, 1, 2]);
+
+        let sections = map.sections(&program);
+        let mut all: Vec<usize> = sections.iter().flat_map(|(_, code)| code.clone()).collect();
+        assert_eq!(sections.last().unwrap(), &(Section::Unreached, vec![program.len() - 2, program.len() - 1]));
+        all.sort_unstable();
+        assert_eq!(all, (0..program.len()).collect::<Vec<_>>());
+    }
+}
@@ -1,10 +1,9 @@
-use rseq_rs::{container, instructions::{OptionalInst, Instruction, asm::AsmParser}, CookieFile};
+use rseq_rs::{container, instructions::{asm, bin}, CookieFile};
 use structopt::StructOpt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::error::Error;
-use std::io::{Read, Write, Cursor, Seek, SeekFrom};
-use nom::combinator::cut;
+use std::io::{BufReader, BufWriter};
 use nom::number::Endianness;
 use cookie_factory::gen;
 
@@ -14,18 +13,47 @@ struct Options {
     #[structopt(parse(from_os_str))]
     input: PathBuf,
     #[structopt(parse(from_os_str))]
-    output: Option<PathBuf>
+    output: Option<PathBuf>,
+    /// Write a listing of offsets, bytes and source lines to this file
+    #[structopt(short = "l", long = "listing", parse(from_os_str))]
+    listing: Option<PathBuf>,
+    /// Write a tab separated map from output offsets to source lines to this file
+    #[structopt(short = "m", long = "source-map", parse(from_os_str))]
+    source_map: Option<PathBuf>
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let Options { input, output } = Options::from_args();
-    let mut asm = String::new();
-    File::open(&input)?.read_to_string(&mut asm)?;
-    let rseq = AsmParser::new().parse(&asm).unwrap();
+    let Options { input, output, listing, source_map } = Options::from_args();
+    let source = BufReader::new(File::open(&input)?);
+    let assembly = match asm::assemble_reader(source, input.parent().unwrap_or(Path::new(""))) {
+        Ok(assembly) => assembly,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}:{}", input.display(), error);
+            }
+            return Err(format!("{} error(s) while assembling", errors.len()).into());
+        }
+    };
+    for warning in &assembly.warnings {
+        eprintln!("{}:{} (warning)", input.display(), warning);
+    }
 
     let mut output = File::create(
         output.unwrap_or_else(|| input.with_extension("brseq"))
     )?;
-    gen(container::gen(&rseq, Endianness::Big), CookieFile(&mut output))?;
+    gen(container::gen(&assembly.rseq, Endianness::Big), CookieFile(&mut output))?;
+
+    if listing.is_some() || source_map.is_some() {
+        let (data, generated) = bin::gen_instructions_vec(&assembly.rseq.instructions, Endianness::Big)?;
+        if let Some(listing) = listing {
+            let source = std::fs::read_to_string(&input)?;
+            let mut out = BufWriter::new(File::create(listing)?);
+            asm::listing::write_listing(&mut out, &source, &assembly, &data, &generated, container::INSTRUCTIONS_OFFSET)?;
+        }
+        if let Some(source_map) = source_map {
+            let mut out = BufWriter::new(File::create(source_map)?);
+            asm::listing::write_source_map(&mut out, &assembly, &generated, data.len(), container::INSTRUCTIONS_OFFSET)?;
+        }
+    }
     Ok(())
 }
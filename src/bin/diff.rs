@@ -0,0 +1,86 @@
+use rseq_rs::container;
+use rseq_rs::analysis::{Program, tracks::TrackMap, diff::{diff, Change}};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::error::Error;
+use std::collections::BTreeSet;
+use nom::combinator::cut;
+
+/// Compares two BRSEQs instruction by instruction, label by label
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-diff")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    old: PathBuf,
+    #[structopt(parse(from_os_str))]
+    new: PathBuf
+}
+
+struct Side<'a> {
+    program: Program<'a>,
+    map: TrackMap,
+    positions: &'a [u32]
+}
+
+impl<'a> Side<'a> {
+    fn new(located: &'a container::Located) -> Side<'a> {
+        let program = Program::new(&located.rseq.instructions);
+        let map = TrackMap::new(&program);
+        Side { program, map, positions: &located.positions }
+    }
+
+    fn line(&self, idx: usize) -> String {
+        format!("0x{:06x}  {}", self.positions[idx], self.program.instructions[idx])
+    }
+
+    fn tracks(&self, idx: usize) -> impl Iterator<Item=u8> + '_ {
+        self.map.owners[idx].iter().map(move |&track| self.map.tracks[track].number)
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { old, new } = Options::from_args();
+    let (old, new) = (std::fs::read(&old)?, std::fs::read(&new)?);
+    let parse = |bytes| match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(bytes) {
+        Ok((_, located)) => Ok(located),
+        Err(err) => Err(err.to_string())
+    };
+    let (old, new) = (parse(&old)?, parse(&new)?);
+    let (old, new) = (Side::new(&old), Side::new(&new));
+
+    let sections = diff(&old.program, &new.program);
+    if sections.is_empty() {
+        println!("No differences");
+    }
+    for section in &sections {
+        let mut tracks = BTreeSet::new();
+        for change in &section.changes {
+            match *change {
+                Change::Removed(a) => tracks.extend(old.tracks(a)),
+                Change::Inserted(b) => tracks.extend(new.tracks(b)),
+                Change::Changed(a, b) => tracks.extend(old.tracks(a).chain(new.tracks(b)))
+            }
+        }
+        let tracks: Vec<String> = tracks.iter().map(|track| track.to_string()).collect();
+        let label = section.label.as_deref().unwrap_or("(start)");
+        let status = match (section.old, section.new) {
+            (None, _) => " (new label)",
+            (_, None) => " (removed label)",
+            _ => ""
+        };
+        if tracks.is_empty() {
+            println!("{}{}, not run by any track:", label, status);
+        } else {
+            println!("{}{}, run by track {}:", label, status, tracks.join(", "));
+        }
+
+        for change in &section.changes {
+            match *change {
+                Change::Removed(a) => println!("  - {}", old.line(a)),
+                Change::Inserted(b) => println!("  + {}", new.line(b)),
+                Change::Changed(a, b) => println!("  ~ {}  =>  {}", old.line(a), new.line(b))
+            }
+        }
+    }
+    Ok(())
+}
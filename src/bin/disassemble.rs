@@ -1,11 +1,12 @@
-use rseq_rs::{container, instructions::{OptionalInst, Instruction, UserOp, Destination}};
+use rseq_rs::{container, container::Located, instructions::{asm, OptionalInst, Instruction}};
+use rseq_rs::analysis::{Program, Flow, tracks::{TrackMap, Section}, timing::{self, Position}};
 use structopt::StructOpt;
 use std::path::PathBuf;
 use std::fs::File;
 use std::error::Error;
 use std::io::{Read, Write};
-use nom::Err;
-use nom::Offset;
+use std::fmt::{self, Write as _};
+use std::collections::HashMap;
 use nom::combinator::cut;
 
 #[derive(StructOpt, Debug)]
@@ -14,69 +15,174 @@ struct Options {
     #[structopt(parse(from_os_str))]
     input: PathBuf,
     #[structopt(parse(from_os_str))]
-    output: Option<PathBuf>
+    output: Option<PathBuf>,
+    /// Decode all of DATA as instructions, instead of only what is reachable from the labels
+    #[structopt(long = "linear")]
+    linear: bool,
+    /// Group the output by track, with an index of the tracks at the top
+    #[structopt(long = "split-tracks")]
+    split_tracks: bool,
+    /// Show each instruction's offset, raw bytes and running tick count, and what refers to each label
+    #[structopt(long = "annotate")]
+    annotate: bool
+}
+
+const ANNOTATION_COLUMN: usize = 32;
+const BYTES_PER_LINE: usize = 16;
+
+/// What `--annotate` shows next to each instruction.
+struct Annotations<'a> {
+    located: &'a Located<'a>,
+    times: Vec<Option<Position>>,
+    /// The instructions that jump, call or fork to each label.
+    xrefs: HashMap<usize, Vec<String>>
+}
+
+impl<'a> Annotations<'a> {
+    fn new(located: &'a Located<'a>, program: &Program) -> Annotations<'a> {
+        let mut xrefs: HashMap<usize, Vec<String>> = HashMap::new();
+        for idx in 0..program.len() {
+            let offset = located.positions[idx];
+            for flow in program.successors(idx) {
+                let (target, what) = match flow {
+                    Flow::Jump(target) => (target, "jump".to_string()),
+                    Flow::Call { target, .. } => (target, "call".to_string()),
+                    Flow::Fork { track, target } => (target, format!("fork {}", track)),
+                    _ => continue
+                };
+                xrefs.entry(target).or_default().push(format!("{} at {:06x}", what, offset));
+            }
+        }
+        Annotations { located, times: timing::linear_positions(program), xrefs }
+    }
+
+    /// The bytes from `start` to the instruction after `last`.
+    fn bytes(&self, start: usize, last: usize) -> &'a [u8] {
+        let end = self.located.positions.get(last + 1).map(|p| *p as usize).unwrap_or_else(|| self.located.data.len());
+        &self.located.data[self.located.positions[start] as usize..end]
+    }
+
+    fn write_line(&self, out: &mut String, text: &str, idx: usize, bytes: &[u8]) -> fmt::Result {
+        let offset = self.located.positions[idx];
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        write!(out, "{:<width$} # {:06x} {:06x}  {:<14}", text, offset, offset + self.located.offset, hex.join(" "), width = ANNOTATION_COLUMN)?;
+        if let Some(time) = self.times[idx] {
+            let (bar, beat, tick) = time.bar_beat();
+            write!(out, " {:>7}  {}:{}.{:02}", time.tick, bar, beat, tick)?;
+        }
+        writeln!(out).map(|_| ())
+    }
+
+    /// Like `asm::write_instructions`, but for the instructions at `indices` and with annotations.
+    fn write(&self, out: &mut String, instructions: &[OptionalInst], indices: &[usize]) -> fmt::Result {
+        let mut i = 0;
+        while i < indices.len() {
+            let idx = indices[i];
+            match &instructions[idx] {
+                OptionalInst::Label(name) => {
+                    if let Some(xrefs) = self.xrefs.get(&idx) {
+                        writeln!(out, "# xrefs: {}", xrefs.join(", "))?;
+                    }
+                    writeln!(out, "{}:", name)?;
+                },
+                OptionalInst::Byte(_) => {
+                    let run = indices[i..].iter().take(BYTES_PER_LINE)
+                        .take_while(|&&idx| matches!(instructions[idx], OptionalInst::Byte(_))).count();
+                    let bytes = self.bytes(idx, indices[i + run - 1]);
+                    let text: Vec<String> = bytes.iter().map(|b| format!("0x{:02x}", b)).collect();
+                    let offset = self.located.positions[idx];
+                    writeln!(out, ".bytes {}  # {:06x} {:06x}", text.join(", "), offset, offset + self.located.offset)?;
+                    i += run;
+                    continue;
+                },
+                OptionalInst::Instruction(Instruction::If) if matches!(indices.get(i + 1).map(|&next| &instructions[next]), Some(OptionalInst::Instruction(_))) => {
+                    let next = indices[i + 1];
+                    self.write_line(out, &format!("? {}", instructions[next]), idx, self.bytes(idx, next))?;
+                    i += 1;
+                },
+                inst => self.write_line(out, &inst.to_string(), idx, self.bytes(idx, idx))?
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the instructions at `indices`, with annotations if there are any.
+fn write_section(out: &mut String, instructions: &[OptionalInst], indices: &[usize], annotations: Option<&Annotations>) -> fmt::Result {
+    match annotations {
+        Some(annotations) => annotations.write(out, instructions, indices),
+        None => {
+            let section: Vec<OptionalInst> = indices.iter().map(|&idx| instructions[idx].clone()).collect();
+            asm::write_instructions(out, &section)
+        }
+    }
+}
+
+fn track_name(map: &TrackMap, program: &Program, track: usize) -> String {
+    let track = &map.tracks[track];
+    format!("track {} ({})", track.number, program.name(track.entry))
+}
+
+/// Writes each track's code in its own section, along with the subroutines it calls and the code it shares with other tracks.
+fn split_tracks(out: &mut String, program: &Program, annotations: Option<&Annotations>) -> fmt::Result {
+    let map = TrackMap::new(program);
+
+    writeln!(out, "# Tracks:")?;
+    for (song, &entry) in map.songs.iter().enumerate() {
+        writeln!(out, "#   song {}", program.name(entry))?;
+        for track in map.tracks.iter().filter(|t| t.song == song) {
+            writeln!(out, "#     track {}: {}", track.number, program.name(track.entry))?;
+        }
+    }
+
+    for (section, code) in map.sections(program) {
+        writeln!(out)?;
+        match section {
+            Section::Track(idx) => {
+                let track = &map.tracks[idx];
+                writeln!(out, "# ==== Track {} of song {}, entry {} ====", track.number, program.name(map.songs[track.song]), program.name(track.entry))?;
+                if !track.calls.is_empty() {
+                    let calls: Vec<String> = track.calls.iter().map(|&sub| program.name(sub)).collect();
+                    writeln!(out, "# Calls: {}", calls.join(", "))?;
+                }
+                for (other, count) in map.shared_with(idx) {
+                    writeln!(out, "# Shares {} instructions with {}", count, track_name(&map, program, other))?;
+                }
+            },
+            Section::Subroutine(entry) => {
+                let callers: Vec<String> = map.callers(entry).into_iter().map(|track| track_name(&map, program, track)).collect();
+                writeln!(out, "# ==== Subroutine {} ====", program.name(entry))?;
+                writeln!(out, "# Called by: {}", callers.join(", "))?;
+            },
+            Section::Unreached => writeln!(out, "# ==== Not run by any track ====")?
+        }
+        write_section(out, program.instructions, &code, annotations)?;
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let Options { input, output } = Options::from_args();
+    let Options { input, output, linear, split_tracks: split, annotate } = Options::from_args();
     let bytes: Result<Vec<u8>, _> = File::open(&input)?.bytes().collect();
     let bytes = bytes?;
 
-    match cut(container::parse::<nom::error::VerboseError<&[u8]>>)(&bytes) {
-        Ok((_, rseq)) => {
+    match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(linear))(&bytes) {
+        Ok((_, located)) => {
             // println!("{:?}", rseq.labels);
-            let mut output = File::create(output.unwrap_or_else(|| input.with_extension("txt")))?;
-            for inst in rseq.instructions {
-                match inst {
-                    OptionalInst::Label(l) => writeln!(output, "{}:", l),
-                    OptionalInst::Byte(b) => writeln!(output, ".byte 0x{:x}", b),
-                    OptionalInst::Instruction(i) => match i {
-                        Instruction::Note { note, velocity, len } => writeln!(output, "note {}, {}, {}", note, velocity, len),
-                        Instruction::Rest(len) => writeln!(output, "rest {}", len),
-                        Instruction::Instrument(value) => writeln!(output, "set Instrument = {}", value),
-                        Instruction::Fork { track, dest: Destination::Label(l) } => writeln!(output, "fork {}, {}", track, l),
-                        Instruction::Jump(Destination::Label(l)) => writeln!(output, "jump {}", l),
-                        Instruction::Call(Destination::Label(l)) => writeln!(output, "call {}", l),
-                        Instruction::If => write!(output, "?"),
-                        Instruction::LoopStart(unk) => writeln!(output, "start_loop {}", unk),
-                        Instruction::PrintVar(var) => writeln!(output, "print _{}", var),
-                        Instruction::UserProcess { op, var, imm } => {
-                            let op_str = match op {
-                                UserOp::Set => "=",
-                                UserOp::Add => "+=",
-                                UserOp::Sub => "-=",
-                                UserOp::Mul => "*=",
-                                UserOp::Div => "/=",
-                                UserOp::Rand => "rand",
-                                UserOp::And => "&=",
-                                UserOp::Or => "|=",
-                                UserOp::Xor => "^=",
-                                UserOp::Not => "~=",
-                                UserOp::Mod => "%=",
-                                UserOp::CmpEq => "==",
-                                UserOp::CmpGe => ">=",
-                                UserOp::CmpGt => ">",
-                                UserOp::CmpLe => "<=",
-                                UserOp::CmpLt => "<",
-                                UserOp::CmpNe => "!=",
-                                UserOp::Shift => "<<=", //if imm < 0 {">>="} else {"<<="},
-                                UserOp::User => "" // special, ends up ignored.
-                            };
-                            match op {
-                                UserOp::User => writeln!(output, "process 0x{:x}", imm as u16),
-                                _ => writeln!(output, "process _{} {} {}", var, op_str, imm)
-                            }
-                        },
-                        Instruction::LoopEnd => writeln!(output, "end_loop"),
-                        Instruction::Return => writeln!(output, "ret"),
-                        Instruction::EndOfTrack => writeln!(output, "end_track"),
-
-                        Instruction::SetU8Param { param, value } => writeln!(output, "set {:?} = {}", param, value),
-                        Instruction::SetU16Param { param, value } => writeln!(output, "set {:?} = {}", param, value),
-                        //_ => writeln!(output, "{:?}", i)
-                    }
-                }?;
+            let program = Program::new(&located.rseq.instructions);
+            let annotations = if annotate { Some(Annotations::new(&located, &program)) } else { None };
+            let mut text = String::new();
+            if annotate {
+                writeln!(text, "# Columns: DATA offset, file offset, bytes, ticks since the code was last jumped to, bar:beat.tick")?;
             }
+            if split {
+                split_tracks(&mut text, &program, annotations.as_ref())?;
+            } else {
+                write_section(&mut text, program.instructions, &(0..program.len()).collect::<Vec<_>>(), annotations.as_ref())?;
+            }
+            let mut output = File::create(output.unwrap_or_else(|| input.with_extension("txt")))?;
+            output.write_all(text.as_bytes())?;
             // for label in rseq.unused_labels {
             //     println!("Warning: Label '{}' at 0x{:x} was not emitted.", label.1, label.0);
             // }
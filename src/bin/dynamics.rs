@@ -0,0 +1,106 @@
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::analysis::Program;
+use rseq_rs::transform::dynamics::{adjust, Dynamics, Step};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use std::collections::BTreeSet;
+use nom::combinator::cut;
+use cookie_factory::gen;
+
+/// Scales, offsets, compresses or curves the velocities and volumes of a BRSEQ
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-dynamics")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// A step like `scale 0.8`, `offset -10`, `compress 96 2` or `curve 1.5`. Steps are applied in order
+    #[structopt(short = "s", long = "step", parse(try_from_str = parse_step), required = true, number_of_values = 1)]
+    steps: Vec<Step>,
+    /// Only change note velocities
+    #[structopt(long = "velocity", conflicts_with = "volume")]
+    velocity: bool,
+    /// Only change Volume, MasterVolume and Expression
+    #[structopt(long = "volume")]
+    volume: bool,
+    /// Only change what these tracks play, like `1,2`
+    #[structopt(long = "tracks", parse(try_from_str = parse_list))]
+    tracks: Option<BTreeSet<u8>>,
+    /// Only change what comes after this label
+    #[structopt(long = "from")]
+    from: Option<String>,
+    /// Only change what comes before this label
+    #[structopt(long = "to")]
+    to: Option<String>
+}
+
+fn parse_list(list: &str) -> Result<BTreeSet<u8>, String> {
+    list.split(',').map(|item| item.trim().parse().map_err(|_| format!("Bad track '{}'", item))).collect()
+}
+
+fn parse_step(spec: &str) -> Result<Step, String> {
+    let words: Vec<&str> = spec.split_whitespace().collect();
+    let number = |word: &str| word.parse::<f64>().ok().filter(|value| value.is_finite()).ok_or(format!("Bad number '{}'", word));
+    match words[..] {
+        ["scale", by] => Ok(Step::Scale(number(by)?)),
+        ["offset", by] => Ok(Step::Offset(number(by)?)),
+        ["compress", threshold, ratio] => match number(ratio)? {
+            ratio if ratio > 0.0 => Ok(Step::Compress { threshold: number(threshold)?, ratio }),
+            _ => Err("The ratio has to be above 0".into())
+        },
+        ["curve", gamma] => match number(gamma)? {
+            gamma if gamma > 0.0 => Ok(Step::Curve(gamma)),
+            _ => Err("The curve has to be above 0".into())
+        },
+        _ => Err(format!("Expected `scale X`, `offset N`, `compress T R` or `curve G`, not '{}'", spec))
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output, steps, velocity, volume, tracks, from, to } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let program = Program::new(&located.rseq.instructions);
+    let label = |name: Option<String>, default| match name {
+        Some(name) => program.label(&name).ok_or(format!("No label called '{}'", name)),
+        None => Ok(default)
+    };
+    let range = if from.is_some() || to.is_some() {
+        Some(label(from, 0)?..label(to, program.len())?)
+    } else {
+        None
+    };
+
+    let both = !velocity && !volume;
+    let dynamics = Dynamics { steps, velocity: velocity || both, volume: volume || both, tracks, range };
+    let adjusted = match adjust(&program, &dynamics) {
+        Ok(adjusted) => adjusted,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}:0x{:06x}: error: {}", input.display(), located.positions[error.idx], error.message);
+            }
+            return Err(format!("{} value(s) couldn't be changed", errors.len()).into());
+        }
+    };
+    for warning in &adjusted.warnings {
+        eprintln!("{}:0x{:06x}: warning: {}", input.display(), located.positions[warning.idx], warning.message);
+    }
+
+    let output = output.unwrap_or_else(|| {
+        let mut new_name = input.file_stem().unwrap().to_owned();
+        new_name.push("_dynamics.brseq");
+        input.with_file_name(new_name)
+    });
+
+    let mut file = File::create(output)?;
+
+    gen(container::gen(&RSEQ { instructions: adjusted.instructions, generated_labels: located.rseq.generated_labels }, located.header.endian), CookieFile(&mut file))?;
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::analysis::Program;
+use rseq_rs::transform::extract::extract;
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use nom::combinator::cut;
+use cookie_factory::gen;
+
+/// Shrinks a BRSEQ by moving repeated runs of instructions into subroutines
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-extract")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let extracted = extract(&Program::new(&located.rseq.instructions));
+    for sub in &extracted.subroutines {
+        println!("{}: {} instructions, called from {} places", sub.name, sub.len, sub.calls);
+    }
+    println!("Saved {} bytes ({} down to {})", extracted.before - extracted.after, extracted.before, extracted.after);
+
+    let output = output.unwrap_or_else(|| {
+        let mut new_name = input.file_stem().unwrap().to_owned();
+        new_name.push("_extracted.brseq");
+        input.with_file_name(new_name)
+    });
+
+    let mut file = File::create(output)?;
+
+    gen(container::gen(&RSEQ { instructions: extracted.instructions, generated_labels: located.rseq.generated_labels }, located.header.endian), CookieFile(&mut file))?;
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::analysis::{Program, lint::VARIABLE_COUNT};
+use rseq_rs::transform::flatten::{flatten, Flatten};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use nom::combinator::cut;
+use cookie_factory::gen;
+
+/// Inlines calls, unrolls loops and decides `?`s, so every track of a BRSEQ is straight-line code
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-flatten")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// What a variable is at the start, like `3=1`. Variables that aren't given start at 0
+    #[structopt(short = "v", long = "var", parse(try_from_str = parse_var), number_of_values = 1)]
+    vars: Vec<(u8, i16)>,
+    /// End tracks that loop forever with `end_track` instead of a `jump` back
+    #[structopt(long = "no-loop")]
+    no_loop: bool
+}
+
+fn parse_var(spec: &str) -> Result<(u8, i16), String> {
+    let (var, value) = spec.split_once('=').ok_or(format!("Expected `var=value`, not '{}'", spec))?;
+    let var = var.trim().trim_start_matches('_');
+    match (var.parse::<u8>(), value.trim().parse::<i16>()) {
+        (Ok(var), Ok(value)) if var < VARIABLE_COUNT => Ok((var, value)),
+        (Ok(var), Ok(_)) => Err(format!("Variable _{} doesn't exist, the last one is _{}", var, VARIABLE_COUNT - 1)),
+        _ => Err(format!("Expected `var=value`, not '{}'", spec))
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output, vars: given, no_loop } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let mut vars = vec![0; VARIABLE_COUNT as usize];
+    for (var, value) in given {
+        vars[var as usize] = value;
+    }
+    let program = Program::new(&located.rseq.instructions);
+    let flattened = match flatten(&program, &Flatten { vars, loops: !no_loop }) {
+        Ok(flattened) => flattened,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}:0x{:06x}: error: {}", input.display(), located.positions[error.idx], error.message);
+            }
+            return Err(format!("{} track(s) couldn't be flattened", errors.len()).into());
+        }
+    };
+    for warning in &flattened.warnings {
+        eprintln!("{}:0x{:06x}: warning: {}", input.display(), located.positions[warning.idx], warning.message);
+    }
+
+    let output = output.unwrap_or_else(|| {
+        let mut new_name = input.file_stem().unwrap().to_owned();
+        new_name.push("_flat.brseq");
+        input.with_file_name(new_name)
+    });
+
+    let mut file = File::create(output)?;
+
+    gen(container::gen(&RSEQ { instructions: flattened.instructions, generated_labels: located.rseq.generated_labels }, located.header.endian), CookieFile(&mut file))?;
+    Ok(())
+}
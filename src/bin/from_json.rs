@@ -0,0 +1,26 @@
+use rseq_rs::{container::{self, Json}, CookieFile};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use std::io::BufReader;
+use cookie_factory::gen;
+
+/// Turns the JSON that to-json writes back into a BRSEQ with the same endianness
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-from-json")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output } = Options::from_args();
+    let json: Json = serde_json::from_reader(BufReader::new(File::open(&input)?))?;
+
+    let mut output = File::create(output.unwrap_or_else(|| input.with_extension("brseq")))?;
+    gen(container::gen(&json.rseq, json.endian.into()), CookieFile(&mut output))?;
+    Ok(())
+}
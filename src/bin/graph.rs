@@ -0,0 +1,64 @@
+use rseq_rs::container;
+use rseq_rs::analysis::{Program, cfg::Cfg, tracks::TrackMap, dot};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::error::Error;
+use std::collections::BTreeSet;
+use nom::combinator::cut;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-graph")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Only include the code run by this track
+    #[structopt(short = "t", long = "track")]
+    track: Option<u8>,
+    /// With --track, which song to take the track from, by its entry label. Defaults to the first song
+    #[structopt(short = "s", long = "song")]
+    song: Option<String>,
+    /// Write the call graph between tracks and subroutines instead of the control flow graph
+    #[structopt(long = "calls")]
+    calls: bool,
+    /// Decode all of DATA as instructions, instead of only what is reachable from the labels
+    #[structopt(long = "linear")]
+    linear: bool
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output, track, song, calls, linear } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+    let rseq = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(linear))(&bytes) {
+        Ok((_, located)) => located.rseq,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let program = Program::new(&rseq.instructions);
+    let map = TrackMap::new(&program);
+    let mut out = String::new();
+    if calls {
+        dot::write_call_graph(&mut out, &program, &map)?;
+    } else {
+        let include = match track {
+            Some(number) => {
+                let song = match &song {
+                    Some(name) => map.songs.iter().position(|&entry| program.label_at(entry) == Some(name.as_str()))
+                        .ok_or_else(|| format!("No song starts at '{}'", name))?,
+                    None => 0
+                };
+                let tracks: Vec<usize> = (0..map.tracks.len()).filter(|&t| map.tracks[t].song == song && map.tracks[t].number == number).collect();
+                if tracks.is_empty() {
+                    return Err(format!("Track {} is never started", number).into());
+                }
+                let code: BTreeSet<usize> = (0..program.len()).filter(|&idx| map.owners[idx].iter().any(|owner| tracks.contains(owner))).collect();
+                Some(code)
+            },
+            None => None
+        };
+        dot::write_cfg(&mut out, &program, &Cfg::new(&program), include.as_ref())?;
+    }
+    std::fs::write(output.unwrap_or_else(|| input.with_extension("dot")), out)?;
+    Ok(())
+}
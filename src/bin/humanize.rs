@@ -0,0 +1,50 @@
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::analysis::Program;
+use rseq_rs::transform::quantize::{humanize, Humanize};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use nom::combinator::cut;
+use cookie_factory::gen;
+
+/// Moves the notes of a BRSEQ and changes their velocities by small random amounts
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-humanize")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// The same seed always gives the same result
+    #[structopt(short = "s", long = "seed", default_value = "0")]
+    seed: u64,
+    /// The most ticks a note can move by
+    #[structopt(short = "t", long = "timing", default_value = "2")]
+    timing: u64,
+    /// The most a velocity can change by
+    #[structopt(short = "v", long = "velocity", default_value = "8")]
+    velocity: u8
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output, seed, timing, velocity } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let instructions = humanize(&Program::new(&located.rseq.instructions), &Humanize { seed, timing, velocity });
+
+    let output = output.unwrap_or_else(|| {
+        let mut new_name = input.file_stem().unwrap().to_owned();
+        new_name.push("_humanized.brseq");
+        input.with_file_name(new_name)
+    });
+
+    let mut file = File::create(output)?;
+
+    gen(container::gen(&RSEQ { instructions, generated_labels: located.rseq.generated_labels }, located.header.endian), CookieFile(&mut file))?;
+    Ok(())
+}
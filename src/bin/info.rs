@@ -0,0 +1,252 @@
+use rseq_rs::{container, instructions::{OptionalInst, Instruction, U16Parameters}};
+use rseq_rs::analysis::{Program, tracks::TrackMap, timing::{self, End}};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::error::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use nom::{combinator::cut, number::Endianness};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-info")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    /// Show how long each track runs before it loops, and tracks that drift out of step with track 0
+    #[structopt(long = "timing")]
+    timing: bool,
+    /// Print everything as JSON instead, which needs the `serde` feature
+    #[structopt(long = "json")]
+    json: bool
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Section {
+    name: &'static str,
+    offset: u32,
+    size: u32
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Label {
+    name: String,
+    offset: u32
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Timing {
+    start: u64,
+    intro_ticks: u64,
+    intro_seconds: f64,
+    /// `None` if the track ends, or if it didn't repeat itself within the step limit.
+    loop_point: Option<String>,
+    loop_ticks: Option<u64>,
+    loop_seconds: Option<f64>,
+    ends: bool,
+    uncertain: bool,
+    drift: Option<String>
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Track {
+    number: u8,
+    entry: String,
+    instruments: BTreeSet<u64>,
+    /// Lowest and highest note.
+    notes: Option<(u8, u8)>,
+    timing: Option<Timing>
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Song {
+    entry: String,
+    /// The tracks that `TrackUsage` allocates.
+    track_usage: BTreeSet<u8>,
+    tracks: Vec<Track>
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Bytes {
+    /// Offset in DATA, like label offsets.
+    offset: u32,
+    length: u32
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Info {
+    endian: &'static str,
+    version: String,
+    file_size: u32,
+    sections: Vec<Section>,
+    labels: Vec<Label>,
+    songs: Vec<Song>,
+    instruments: BTreeSet<u64>,
+    /// Every value each parameter gets set to.
+    parameters: BTreeMap<String, BTreeSet<u16>>,
+    unknown_bytes: Vec<Bytes>
+}
+
+fn gather(located: &container::Located, timing: bool) -> Info {
+    let header = &located.header;
+    let instructions = &located.rseq.instructions;
+    let program = Program::new(instructions);
+    let map = TrackMap::new(&program);
+
+    let songs = map.songs.iter().enumerate().map(|(song, &entry)| {
+        let timing = if timing { Some(timing::song_timing(&program, &map, song)) } else { None };
+        let mut track_usage = BTreeSet::new();
+        let tracks = map.tracks.iter().enumerate().filter(|(_, track)| track.song == song).map(|(idx, track)| {
+            let code = track.code.iter().chain(track.calls.iter().flat_map(|sub| &map.subroutines[sub].code));
+            let mut instruments = BTreeSet::new();
+            let mut notes: Option<(u8, u8)> = None;
+            for inst in code.filter_map(|&idx| program.instruction(idx)) {
+                match inst.unprefixed() {
+                    // A prefixed instrument only has a placeholder.
+                    Instruction::Instrument(value) if inst.unprefixed() == inst => { instruments.insert(*value); },
+                    Instruction::Note { note, .. } => notes = Some(notes.map_or((*note, *note), |(low, high)| (low.min(*note), high.max(*note)))),
+                    Instruction::SetU16Param { param: U16Parameters::TrackUsage, value } =>
+                        track_usage.extend((0..16).filter(|bit| value & (1 << bit) != 0)),
+                    _ => ()
+                }
+            }
+            let timing = timing.as_ref().and_then(|timing| timing.tracks.iter().find(|t| t.track == idx).map(|t| {
+                let looped = match t.end {
+                    End::Loops { point, length } => Some((program.name(point), length)),
+                    _ => None
+                };
+                Timing {
+                    start: t.start,
+                    intro_ticks: t.intro.ticks,
+                    intro_seconds: t.intro.seconds,
+                    loop_point: looped.as_ref().map(|(point, _)| point.clone()),
+                    loop_ticks: looped.as_ref().map(|(_, length)| length.ticks),
+                    loop_seconds: looped.as_ref().map(|(_, length)| length.seconds),
+                    ends: t.end == End::Ends,
+                    uncertain: t.uncertain,
+                    drift: timing.drift.iter().find(|(track, _)| *track == idx).map(|(_, problem)| problem.clone())
+                }
+            }));
+            Track { number: track.number, entry: program.name(track.entry), instruments, notes, timing }
+        }).collect();
+        Song { entry: program.name(entry), track_usage, tracks }
+    }).collect();
+
+    let mut parameters: BTreeMap<String, BTreeSet<u16>> = BTreeMap::new();
+    let mut instruments = BTreeSet::new();
+    for inst in (0..program.len()).filter_map(|idx| program.instruction(idx)) {
+        match inst {
+            Instruction::Instrument(value) => { instruments.insert(*value); },
+            Instruction::SetU8Param { param, value } => { parameters.entry(format!("{:?}", param)).or_default().insert((*value).into()); },
+            Instruction::SetU16Param { param, value } => { parameters.entry(format!("{:?}", param)).or_default().insert(*value); },
+            _ => ()
+        }
+    }
+
+    let mut unknown_bytes: Vec<Bytes> = Vec::new();
+    for (idx, inst) in instructions.iter().enumerate() {
+        if let OptionalInst::Byte(_) = inst {
+            let offset = located.positions[idx];
+            match unknown_bytes.last_mut() {
+                Some(run) if run.offset + run.length == offset => run.length += 1,
+                _ => unknown_bytes.push(Bytes { offset, length: 1 })
+            }
+        }
+    }
+
+    Info {
+        endian: if header.endian == Endianness::Big { "big" } else { "little" },
+        version: format!("{}.{}", header.version >> 8, header.version & 0xFF),
+        file_size: header.file_size,
+        sections: vec![
+            Section { name: "DATA", offset: header.data.0, size: header.data.1 },
+            Section { name: "LABL", offset: header.labl.0, size: header.labl.1 }
+        ],
+        labels: header.labels.iter().map(|(offset, name)| Label { name: name.clone(), offset: *offset }).collect(),
+        songs,
+        instruments,
+        parameters,
+        unknown_bytes
+    }
+}
+
+fn list<T: ToString>(items: impl IntoIterator<Item=T>) -> String {
+    let items: Vec<String> = items.into_iter().map(|item| item.to_string()).collect();
+    if items.is_empty() { "none".into() } else { items.join(", ") }
+}
+
+fn print(info: &Info, data_offset: u32) {
+    println!("Endianness: {}", info.endian);
+    println!("Version: {}", info.version);
+    println!("File size: 0x{:x}", info.file_size);
+    for section in &info.sections {
+        println!("{}: 0x{:x} bytes at 0x{:x}", section.name, section.size, section.offset);
+    }
+
+    println!("{} labels:", info.labels.len());
+    for label in &info.labels {
+        println!("  0x{:06x} {}", label.offset, label.name);
+    }
+
+    for song in &info.songs {
+        println!("Song {}, TrackUsage allocates tracks {}", song.entry, list(&song.track_usage));
+        for track in &song.tracks {
+            let notes = track.notes.map(|(low, high)| format!("{} to {}", low, high)).unwrap_or_else(|| "none".into());
+            println!("  Track {} ({}): instruments {}, notes {}", track.number, track.entry, list(&track.instruments), notes);
+            if let Some(timing) = &track.timing {
+                print!("    ");
+                if timing.start != 0 {
+                    print!("Starts at tick {}, ", timing.start);
+                }
+                match (&timing.loop_point, timing.loop_ticks, timing.loop_seconds) {
+                    (Some(point), Some(ticks), Some(seconds)) => print!("intro of {} ticks ({:.3}s), then loops back to {} every {} ticks ({:.3}s)",
+                        timing.intro_ticks, timing.intro_seconds, point, ticks, seconds),
+                    _ if timing.ends => print!("ends after {} ticks ({:.3}s)", timing.intro_ticks, timing.intro_seconds),
+                    _ => print!("still going after {} instructions and {} ticks ({:.3}s)", timing::MAX_STEPS, timing.intro_ticks, timing.intro_seconds)
+                }
+                if timing.uncertain {
+                    print!(" (depends on random numbers or user callbacks)");
+                }
+                println!();
+                if let Some(drift) = &timing.drift {
+                    println!("    Drifts out of sync with track 0: {}", drift);
+                }
+            }
+        }
+    }
+
+    println!("Instruments: {}", list(&info.instruments));
+    println!("Parameters set:");
+    for (param, values) in &info.parameters {
+        println!("  {}: {}", param, list(values));
+    }
+    if info.unknown_bytes.is_empty() {
+        println!("Unknown bytes: none");
+    } else {
+        println!("Unknown bytes:");
+    }
+    for run in &info.unknown_bytes {
+        println!("  {} at 0x{:06x} (file offset 0x{:x})", run.length, run.offset, run.offset + data_offset);
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let options = Options::from_args();
+    if options.json && !cfg!(feature = "serde") {
+        return Err("--json needs the serde feature, rebuild with --features serde".into());
+    }
+    let bytes = std::fs::read(&options.input)?;
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let info = gather(&located, options.timing);
+    #[cfg(feature = "serde")]
+    {
+        if options.json {
+            println!("{}", serde_json::to_string_pretty(&info)?);
+            return Ok(());
+        }
+    }
+    print(&info, located.offset);
+    Ok(())
+}
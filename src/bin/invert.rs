@@ -1,11 +1,11 @@
-use rseq_rs::{container, instructions::{OptionalInst, Instruction}, CookieFile};
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::analysis::Program;
+use rseq_rs::transform::remap::{remap, Mapping, Rule, OutOfRange};
 use structopt::StructOpt;
 use std::path::PathBuf;
 use std::fs::File;
 use std::error::Error;
-use std::io::{Read, Write, Cursor};
 use nom::combinator::cut;
-use nom::number::Endianness;
 use cookie_factory::gen;
 
 #[derive(StructOpt, Debug)]
@@ -19,16 +19,23 @@ struct Options {
 
 fn main() -> Result<(), Box<dyn Error>> {
     let Options { input, output } = Options::from_args();
-    let bytes: Result<Vec<u8>, _> = File::open(&input)?.bytes().collect();
-    let bytes = bytes?;
+    let bytes = std::fs::read(&input)?;
 
-    let (_, mut rseq) = cut(container::parse::<nom::error::VerboseError<&[u8]>>)(&bytes).unwrap();
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
 
-    for mut instruction in &mut rseq.instructions {
-        if let OptionalInst::Instruction(Instruction::Note {ref mut note, ..}) = instruction {
-            *note = 0x7F - *note;
+    let mirror = Rule { mapping: Mapping::Mirror { sum: 0x7F }, tracks: None, instruments: None };
+    let instructions = match remap(&Program::new(&located.rseq.instructions), &[mirror], OutOfRange::Clamp) {
+        Ok(instructions) => instructions,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}:0x{:06x}: error: {}", input.display(), located.positions[error.idx], error.message);
+            }
+            return Err(format!("{} note(s) couldn't be inverted", errors.len()).into());
         }
-    }
+    };
 
     let output = output.unwrap_or_else(|| {
         let mut new_name = input.file_stem().unwrap().to_owned();
@@ -38,6 +45,6 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut file = File::create(output)?;
 
-    gen(container::gen(&rseq, Endianness::Big), CookieFile(&mut file))?;
+    gen(container::gen(&RSEQ { instructions, generated_labels: located.rseq.generated_labels }, located.header.endian), CookieFile(&mut file))?;
     Ok(())
 }
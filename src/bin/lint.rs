@@ -0,0 +1,51 @@
+use rseq_rs::{container, instructions::asm};
+use rseq_rs::analysis::{Program, lint::{lint, Severity}};
+use structopt::StructOpt;
+use std::path::{Path, PathBuf};
+use std::error::Error;
+use nom::combinator::cut;
+
+/// Checks a BRSEQ, or an assembly file, for mistakes that would make it misbehave on hardware
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-lint")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+
+    // BRSEQs are reported by offset into DATA, and assembly by line and column.
+    let (rseq, locations): (_, Vec<String>) = if bytes.starts_with(b"RSEQ") {
+        match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+            Ok((_, located)) => {
+                let locations = located.positions.iter().map(|pos| format!("0x{:06x}", pos)).collect();
+                (located.rseq, locations)
+            },
+            Err(err) => return Err(err.to_string().into())
+        }
+    } else {
+        let source = String::from_utf8(bytes)?;
+        match asm::assemble(&source, input.parent().unwrap_or(Path::new(""))) {
+            Ok(assembly) => (assembly.rseq, assembly.spans.iter().map(|span| span.to_string()).collect()),
+            Err(errors) => {
+                for error in &errors {
+                    eprintln!("{}:{}", input.display(), error);
+                }
+                return Err(format!("{} error(s) while assembling", errors.len()).into());
+            }
+        }
+    };
+
+    let found = lint(&Program::new(&rseq.instructions));
+    for lint in &found {
+        println!("{}:{}: {}: {}", input.display(), locations[lint.idx], lint.severity, lint.message);
+    }
+    let errors = found.iter().filter(|lint| lint.severity == Severity::Error).count();
+    if errors > 0 {
+        return Err(format!("{} error(s), {} warning(s)", errors, found.len() - errors).into());
+    }
+    Ok(())
+}
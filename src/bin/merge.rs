@@ -0,0 +1,58 @@
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::transform::merge::{merge, Part};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use std::collections::BTreeSet;
+use nom::combinator::cut;
+use cookie_factory::gen;
+
+/// Puts several BRSEQs into one, like adding new songs to an existing bank. The first one stays where it is
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-merge")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    output: PathBuf,
+    #[structopt(parse(from_os_str), required = true, min_values = 2)]
+    inputs: Vec<PathBuf>,
+    /// Put each file's name in front of all its labels, not just the ones that are already taken
+    #[structopt(short = "p", long = "prefix")]
+    prefix: bool
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { output, inputs, prefix } = Options::from_args();
+    let mut files = Vec::new();
+    for input in &inputs {
+        let bytes = std::fs::read(input)?;
+        let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+            Ok((_, located)) => located,
+            Err(err) => return Err(format!("{}: {}", input.display(), err).into())
+        };
+        files.push((located.rseq.instructions, located.header.endian, located.rseq.generated_labels));
+    }
+
+    let parts: Vec<Part> = inputs.iter().zip(&files).map(|(input, (instructions, _, _))| Part {
+        instructions,
+        name: input.file_stem().unwrap().to_string_lossy().into_owned(),
+        prefix
+    }).collect();
+    let merged = merge(&parts);
+    for (part, old, new) in &merged.renamed {
+        println!("{}: {} is now {}", inputs[*part].display(), old, new);
+    }
+
+    // Labels the disassembler made up stay out of LABL under their new names too.
+    let generated_labels: BTreeSet<String> = files.iter().enumerate().flat_map(|(part, (_, _, generated))| {
+        let renamed = &merged.renamed;
+        generated.iter().map(move |old| renamed.iter()
+            .find(|(renamed_part, renamed_old, _)| *renamed_part == part && renamed_old == old)
+            .map_or_else(|| old.clone(), |(_, _, new)| new.clone()))
+    }).collect();
+
+    let mut file = File::create(output)?;
+
+    gen(container::gen(&RSEQ { instructions: merged.instructions, generated_labels }, files[0].1), CookieFile(&mut file))?;
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::instructions::OptionalInst;
+use rseq_rs::transform::optimize::optimize;
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use nom::combinator::cut;
+use cookie_factory::gen;
+
+/// Removes and merges redundant instructions in a BRSEQ
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-optimize")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let count = |instructions: &[OptionalInst]| instructions.iter().filter(|inst| matches!(inst, OptionalInst::Instruction(_))).count();
+    let before = count(&located.rseq.instructions);
+    let instructions = optimize(located.rseq.instructions);
+    let size: u32 = instructions.iter().map(OptionalInst::encoded_len).sum();
+    println!("Removed {} instructions, saving {} bytes ({} down to {})",
+        before - count(&instructions), located.data.len() as u32 - size, located.data.len(), size);
+
+    let output = output.unwrap_or_else(|| {
+        let mut new_name = input.file_stem().unwrap().to_owned();
+        new_name.push("_optimized.brseq");
+        input.with_file_name(new_name)
+    });
+
+    let mut file = File::create(output)?;
+
+    gen(container::gen(&RSEQ { instructions, generated_labels: located.rseq.generated_labels }, located.header.endian), CookieFile(&mut file))?;
+    Ok(())
+}
@@ -72,6 +72,16 @@ impl<'a> Track<'a> {
     }
 }
 
+/// Fills in the last argument of a prefixed instruction.
+/// Random numbers come out in the middle of their range.
+fn resolve(inst: Instruction, variables: &[i16]) -> Instruction {
+    match inst {
+        Instruction::Random { min, max, .. } => inst.with_last_arg(((i32::from(min) + i32::from(max)) / 2) as i16),
+        Instruction::Variable { var, .. } => inst.with_last_arg(variables.get(var as usize).copied().unwrap_or(0)),
+        inst => inst
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let Options { input, output, timebase } = Options::from_args();
     let bytes: Result<Vec<u8>, _> = File::open(&input)?.bytes().collect();
@@ -113,7 +123,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             match rseq.instructions[track.instruction_pos].clone() {
                 OptionalInst::Label(_) => (),
                 OptionalInst::Byte(b) => println!("Warning: Tried to execute unknown byte 0x{:x}", b),
-                OptionalInst::Instruction(inst) => match inst {
+                OptionalInst::Instruction(inst) => match resolve(inst, &track.variables) {
                     Instruction::Note { note, velocity: vel, len} => {
                         track.push_midi_event(MidiMessage::NoteOn { key: note.into(), vel: vel.into() });
                         // println!("new note_pos: {}", track.tick_pos + len);
@@ -224,7 +234,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                         _ => println!("Unimplemented param {:?} = {}", param, value)
                     }
 
-                    _ => println!("Unimplemented command: {:?}", inst),
+                    inst => println!("Unimplemented command: {:?}", inst),
                 }
             }
 
@@ -0,0 +1,56 @@
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::analysis::Program;
+use rseq_rs::transform::quantize::{quantize, Grid};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use nom::combinator::cut;
+use cookie_factory::gen;
+
+/// Lines the notes and rests of a BRSEQ up to a grid, keeping the length of every track
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-quantize")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// The grid, as a fraction of a whole note like `1/16`, or in ticks like `12`
+    #[structopt(short = "g", long = "grid", parse(try_from_str = parse_grid))]
+    grid: Grid
+}
+
+fn parse_grid(grid: &str) -> Result<Grid, String> {
+    let number = |text: &str| text.trim().parse::<u64>().ok().filter(|&n| n > 0).ok_or(format!("Bad grid '{}'", grid));
+    match grid.split('/').collect::<Vec<_>>()[..] {
+        ["1", steps] => Ok(Grid::PerWhole(number(steps)?)),
+        [ticks] => Ok(Grid::Ticks(number(ticks)?)),
+        _ => Err(format!("Expected a grid like 1/16 or 12, not '{}'", grid))
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output, grid } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let quantized = quantize(&Program::new(&located.rseq.instructions), grid);
+    for warning in &quantized.warnings {
+        eprintln!("{}:0x{:06x}: warning: {}", input.display(), located.positions[warning.idx], warning.message);
+    }
+
+    let output = output.unwrap_or_else(|| {
+        let mut new_name = input.file_stem().unwrap().to_owned();
+        new_name.push("_quantized.brseq");
+        input.with_file_name(new_name)
+    });
+
+    let mut file = File::create(output)?;
+
+    gen(container::gen(&RSEQ { instructions: quantized.instructions, generated_labels: located.rseq.generated_labels }, located.header.endian), CookieFile(&mut file))?;
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::analysis::Program;
+use rseq_rs::transform::remap::{remap, parse_key_map, Mapping, Rule, OutOfRange};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use std::collections::BTreeSet;
+use nom::combinator::cut;
+use cookie_factory::gen;
+
+/// Transposes, mirrors or remaps the notes of a BRSEQ, optionally only on some tracks or instruments
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-remap")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// A rule like `transpose -12`, `mirror 63.5` or `keymap drums.txt`, optionally followed by
+    /// `track 1,2` and `instrument 5` to limit it. The first rule that matches a note is used
+    #[structopt(short = "r", long = "rule", parse(try_from_str = parse_rule), required = true, number_of_values = 1)]
+    rules: Vec<Rule>,
+    /// Fail on notes that would go outside 0 to 127, instead of clamping them
+    #[structopt(long = "reject")]
+    reject: bool
+}
+
+fn parse_list<T: std::str::FromStr + Ord>(list: &str) -> Result<BTreeSet<T>, String> {
+    list.split(',').map(|item| item.trim().parse().map_err(|_| format!("Bad number '{}'", item))).collect()
+}
+
+fn parse_rule(spec: &str) -> Result<Rule, String> {
+    let words: Vec<&str> = spec.split_whitespace().collect();
+    let mapping = match words[..] {
+        ["transpose", by, ..] => Mapping::Transpose(by.parse().map_err(|_| format!("Bad number of semitones '{}'", by))?),
+        ["mirror", pivot, ..] => {
+            let pivot: f64 = pivot.parse().map_err(|_| format!("Bad note '{}'", pivot))?;
+            if (pivot * 2.0).fract() != 0.0 {
+                return Err("Can only mirror around a note, or halfway between two".into());
+            }
+            Mapping::Mirror { sum: (pivot * 2.0) as i16 }
+        },
+        ["keymap", path, ..] => {
+            let text = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+            Mapping::KeyMap(parse_key_map(&text).map_err(|err| format!("{}:{}", path, err))?)
+        },
+        _ => return Err(format!("Expected transpose, mirror or keymap in '{}'", spec))
+    };
+
+    let mut rule = Rule { mapping, tracks: None, instruments: None };
+    for limit in words[2..].chunks(2) {
+        match limit {
+            ["track", list] => rule.tracks = Some(parse_list(list)?),
+            ["instrument", list] => rule.instruments = Some(parse_list(list)?),
+            _ => return Err(format!("Expected `track` or `instrument` and a list in '{}'", spec))
+        }
+    }
+    Ok(rule)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output, rules, reject } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let out_of_range = if reject { OutOfRange::Reject } else { OutOfRange::Clamp };
+    let instructions = match remap(&Program::new(&located.rseq.instructions), &rules, out_of_range) {
+        Ok(instructions) => instructions,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}:0x{:06x}: error: {}", input.display(), located.positions[error.idx], error.message);
+            }
+            return Err(format!("{} note(s) couldn't be remapped", errors.len()).into());
+        }
+    };
+
+    let output = output.unwrap_or_else(|| {
+        let mut new_name = input.file_stem().unwrap().to_owned();
+        new_name.push("_remapped.brseq");
+        input.with_file_name(new_name)
+    });
+
+    let mut file = File::create(output)?;
+
+    gen(container::gen(&RSEQ { instructions, generated_labels: located.rseq.generated_labels }, located.header.endian), CookieFile(&mut file))?;
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::analysis::Program;
+use rseq_rs::transform::remap::{remap_instruments, parse_instrument_map, OutOfRange};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use nom::combinator::cut;
+use cookie_factory::gen;
+
+/// Changes the instruments of a BRSEQ to the ones another bank uses, transposing their notes if needed
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-remap-instruments")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// A file with one `from to` pair of instruments per line, optionally followed by semitones to transpose by
+    #[structopt(short = "m", long = "map", parse(from_os_str))]
+    map: PathBuf,
+    /// Fail on notes that would go outside 0 to 127, instead of clamping them
+    #[structopt(long = "reject")]
+    reject: bool
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output, map, reject } = Options::from_args();
+    let table = parse_instrument_map(&std::fs::read_to_string(&map)?).map_err(|err| format!("{}:{}", map.display(), err))?;
+    let bytes = std::fs::read(&input)?;
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let out_of_range = if reject { OutOfRange::Reject } else { OutOfRange::Clamp };
+    let instructions = match remap_instruments(&Program::new(&located.rseq.instructions), &table, out_of_range) {
+        Ok(instructions) => instructions,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}:0x{:06x}: error: {}", input.display(), located.positions[error.idx], error.message);
+            }
+            return Err(format!("{} instruction(s) couldn't be remapped", errors.len()).into());
+        }
+    };
+
+    let output = output.unwrap_or_else(|| {
+        let mut new_name = input.file_stem().unwrap().to_owned();
+        new_name.push("_instruments.brseq");
+        input.with_file_name(new_name)
+    });
+
+    let mut file = File::create(output)?;
+
+    gen(container::gen(&RSEQ { instructions, generated_labels: located.rseq.generated_labels }, located.header.endian), CookieFile(&mut file))?;
+    Ok(())
+}
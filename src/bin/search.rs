@@ -0,0 +1,84 @@
+use rseq_rs::container;
+use rseq_rs::analysis::{Program, search::Pattern};
+use structopt::StructOpt;
+use std::path::{Path, PathBuf};
+use std::error::Error;
+use nom::combinator::cut;
+
+/// Finds instructions matching a pattern in BRSEQs, and in BRSARs that have BRSEQs inside them
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-search")]
+struct Options {
+    /// An instruction in assembler syntax, where `*` matches anything, like `set Pan = *` or `fork 15, *`
+    pattern: Pattern,
+    /// Files to search. Directories are searched for .brseq and .brsar files
+    #[structopt(parse(from_os_str), required = true)]
+    inputs: Vec<PathBuf>
+}
+
+fn is_searchable(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("brseq") || ext.eq_ignore_ascii_case("brsar"))
+}
+
+/// Every file under `path`, sorted so that the output doesn't depend on the filesystem.
+fn collect(path: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_owned());
+        return Ok(());
+    }
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(path)?.map(|entry| entry.map(|entry| entry.path())).collect::<Result<_, _>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            collect(&entry, files)?;
+        } else if is_searchable(&entry) {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Searches one BRSEQ, starting at `start` in the file. Returns the number of matches.
+fn search(pattern: &Pattern, path: &Path, bytes: &[u8], start: usize) -> Result<usize, String> {
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes[start..]) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string())
+    };
+    let program = Program::new(&located.rseq.instructions);
+    let found = pattern.find(&program);
+    for &idx in &found {
+        let label = (0..=idx).rev().find_map(|idx| program.label_at(idx)).unwrap_or("-");
+        let guard = if program.is_guarded(idx) { "? " } else { "" };
+        let location = if start == 0 { path.display().to_string() } else { format!("{}@0x{:x}", path.display(), start) };
+        println!("{}:0x{:06x}:{}: {}{}", location, located.positions[idx], label, guard, program.instructions[idx]);
+    }
+    Ok(found.len())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { pattern, inputs } = Options::from_args();
+    let mut files = Vec::new();
+    for input in &inputs {
+        collect(input, &mut files)?;
+    }
+
+    let mut matches = 0;
+    for path in &files {
+        let bytes = std::fs::read(path)?;
+        // A BRSEQ starts with its magic, and a BRSAR has them one after another in its FILE section.
+        let starts = bytes.windows(4).enumerate().filter(|(_, window)| *window == b"RSEQ").map(|(start, _)| start);
+        for start in starts {
+            match search(&pattern, path, &bytes, start) {
+                Ok(found) => matches += found,
+                // The magic can turn up by chance inside archives, but not at the start of a file.
+                Err(err) if start == 0 => eprintln!("{}: {}", path.display(), err),
+                Err(_) => ()
+            }
+        }
+    }
+    if matches == 0 {
+        return Err("No matches".into());
+    }
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::analysis::Program;
+use rseq_rs::transform::merge::split;
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use nom::combinator::cut;
+use cookie_factory::gen;
+
+/// Pulls one song out of a BRSEQ, with everything it plays and nothing else
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-split")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// The label the song starts at
+    #[structopt(short = "l", long = "label")]
+    label: String
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output, label } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let program = Program::new(&located.rseq.instructions);
+    let instructions = split(&program, &label).ok_or(format!("No label called '{}'", label))?;
+
+    let output = output.unwrap_or_else(|| {
+        let mut new_name = input.file_stem().unwrap().to_owned();
+        new_name.push(format!("_{}.brseq", label));
+        input.with_file_name(new_name)
+    });
+
+    let mut file = File::create(output)?;
+
+    gen(container::gen(&RSEQ { instructions, generated_labels: located.rseq.generated_labels }, located.header.endian), CookieFile(&mut file))?;
+    Ok(())
+}
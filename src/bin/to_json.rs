@@ -0,0 +1,34 @@
+use rseq_rs::container::{self, Json};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use std::io::BufWriter;
+use nom::combinator::cut;
+
+/// Writes the instructions of a BRSEQ out as JSON, along with its endianness
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-to-json")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Decode all of DATA as instructions, instead of only what is reachable from the labels
+    #[structopt(long = "linear")]
+    linear: bool
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output, linear } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+
+    let json = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(linear))(&bytes) {
+        Ok((_, located)) => Json { endian: located.header.endian.into(), rseq: located.rseq },
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let output = BufWriter::new(File::create(output.unwrap_or_else(|| input.with_extension("json")))?);
+    serde_json::to_writer_pretty(output, &json)?;
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use rseq_rs::{container::{self, RSEQ}, CookieFile};
+use rseq_rs::analysis::{Program, tracks::TrackMap};
+use rseq_rs::transform::tracks::{select, Tracks};
+use structopt::StructOpt;
+use std::path::PathBuf;
+use std::fs::File;
+use std::error::Error;
+use std::collections::BTreeSet;
+use nom::combinator::cut;
+use cookie_factory::gen;
+
+/// Drops or mutes tracks of a BRSEQ, or keeps only some of them
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rseq-tracks")]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Tracks to not start at all, like `1,2`
+    #[structopt(long = "drop", parse(try_from_str = parse_list))]
+    drop: Option<BTreeSet<u8>>,
+    /// Tracks to start but end straight away
+    #[structopt(long = "mute", parse(try_from_str = parse_list))]
+    mute: Option<BTreeSet<u8>>,
+    /// Tracks to keep, dropping every other one apart from track 0
+    #[structopt(long = "keep", parse(try_from_str = parse_list), conflicts_with = "drop")]
+    keep: Option<BTreeSet<u8>>
+}
+
+fn parse_list(list: &str) -> Result<BTreeSet<u8>, String> {
+    list.split(',').map(|item| match item.trim().parse() {
+        Ok(track) if track < 16 => Ok(track),
+        _ => Err(format!("Bad track '{}'", item))
+    }).collect()
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Options { input, output, drop, mute, keep } = Options::from_args();
+    let bytes = std::fs::read(&input)?;
+    let located = match cut(container::parse_located::<nom::error::VerboseError<&[u8]>>(false))(&bytes) {
+        Ok((_, located)) => located,
+        Err(err) => return Err(err.to_string().into())
+    };
+
+    let program = Program::new(&located.rseq.instructions);
+    let tracks = Tracks { drop: drop.unwrap_or_default(), mute: mute.unwrap_or_default(), keep };
+    if tracks.drop.contains(&0) || tracks.mute.contains(&0) {
+        return Err("Track 0 starts the song, so it has to stay".into());
+    }
+    let forked: BTreeSet<u8> = TrackMap::new(&program).tracks.iter().map(|track| track.number).collect();
+    for track in tracks.drop.iter().chain(&tracks.mute).chain(tracks.keep.iter().flatten()) {
+        if !forked.contains(track) {
+            eprintln!("{}: warning: Nothing starts track {}", input.display(), track);
+        }
+    }
+
+    let selected = select(&program, &tracks);
+    for warning in &selected.warnings {
+        eprintln!("{}:0x{:06x}: warning: {}", input.display(), located.positions[warning.idx], warning.message);
+    }
+    println!("Removed {} instructions and labels", selected.removed);
+
+    let output = output.unwrap_or_else(|| {
+        let mut new_name = input.file_stem().unwrap().to_owned();
+        new_name.push("_tracks.brseq");
+        input.with_file_name(new_name)
+    });
+
+    let mut file = File::create(output)?;
+
+    gen(container::gen(&RSEQ { instructions: selected.instructions, generated_labels: located.rseq.generated_labels }, located.header.endian), CookieFile(&mut file))?;
+    Ok(())
+}
@@ -22,6 +22,9 @@ fn gen_section<W: Write + BackToTheBuffer, F: SerializeFn<W>>(name: [u8; 4], end
     )
 }
 
+/// File offset that `gen_rseq` places the first instruction at.
+pub const INSTRUCTIONS_OFFSET: u32 = 0x20 + 0xC;
+
 fn gen_data_section<'a, W: Write + BackToTheBuffer + Seek>(instructions: &'a Vec<OptionalInst>, endian: Endianness) -> impl Fn(WriteContext<W>) -> Result<(WriteContext<W>, Vec<(u32, String)>), GenError> + 'a {
     move |ctx| {
         // Workaround: cookie_factory wants Fn, not FnMut.
@@ -29,8 +32,8 @@ fn gen_data_section<'a, W: Write + BackToTheBuffer + Seek>(instructions: &'a Vec
         let ret = gen_section(*b"DATA", endian, // section name, len
             pair(gu32(0xC, endian), // section header len
                 |ctx| {
-                    let (ctx, lab) = instructions::bin::gen_instructions(&instructions, endian)(ctx)?;
-                    *labels.lock().unwrap() = Some(lab);
+                    let (ctx, generated) = instructions::bin::gen_instructions(instructions, endian)(ctx)?;
+                    *labels.lock().unwrap() = Some(generated.labels);
                     Ok(ctx)
                 }
             )
@@ -92,6 +95,8 @@ pub fn gen_rseq<'a, W: Write + BackToTheBuffer + Seek + 'a>(rseq: &'a RSEQ, endi
                         //let ctx = slice(&rseq.data)(ctx)?;
                         let mid_pos = ctx.position;
 
+                        // Labels made up by the disassembler aren't really there, and wouldn't match their address after an edit anyway.
+                        labels.retain(|(_, label)| !rseq.generated_labels.contains(label));
                         labels.sort_unstable_by(|(_, a), (_, b)| a.cmp(b));
                         let ctx = gen_labl_section(labels, endian)(ctx)?;
 
@@ -110,3 +115,52 @@ pub fn gen_rseq<'a, W: Write + BackToTheBuffer + Seek + 'a>(rseq: &'a RSEQ, endi
         )
     ))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::container::parse_located;
+    use crate::instructions::asm::assemble;
+    use std::path::Path;
+
+    fn gen(rseq: &RSEQ) -> Vec<u8> {
+        gen_with(rseq, Endianness::Big)
+    }
+
+    fn gen_with(rseq: &RSEQ, endian: Endianness) -> Vec<u8> {
+        let mut bytes = vec![0; 0x1000];
+        let (_, len) = cookie_factory::gen(gen_rseq(rseq, endian), std::io::Cursor::new(&mut bytes[..])).unwrap();
+        bytes.truncate(len as usize);
+        bytes
+    }
+
+    #[test]
+    fn test_labl_round_trip() {
+        let source = "main:\njump target\nrest 1\ntarget:\nend_track\nloc_000010:\nend_track\n";
+        let mut rseq = assemble(source, Path::new("")).unwrap().rseq;
+        // `target` stands in for a jump target that LABL doesn't have, and `loc_000010` is a real label.
+        rseq.generated_labels.insert("target".into());
+        let bytes = gen(&rseq);
+        let (_, located) = parse_located::<nom::error::VerboseError<&[u8]>>(false)(&bytes).unwrap();
+        assert_eq!(located.header.labels, [(0, "main".to_string()), (7, "loc_000010".to_string())]);
+        assert_eq!(located.rseq.generated_labels.iter().collect::<Vec<_>>(), ["loc_000006"]);
+        assert!(located.rseq.instructions.contains(&OptionalInst::Label("loc_000006".into())));
+        assert_eq!(gen(&located.rseq), bytes);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_round_trip() {
+        use crate::container::Json;
+
+        let rseq = assemble("main:\nnote 60, 100, 48\nset Tempo = 300\nend_track\n", Path::new("")).unwrap().rseq;
+        let bytes = gen_with(&rseq, Endianness::Little);
+        let (_, located) = parse_located::<nom::error::VerboseError<&[u8]>>(false)(&bytes).unwrap();
+        let json = serde_json::to_string(&Json { endian: located.header.endian.into(), rseq: located.rseq }).unwrap();
+        let json: Json = serde_json::from_str(&json).unwrap();
+        assert_eq!(gen_with(&json.rseq, json.endian.into()), bytes);
+
+        let old: Json = serde_json::from_str(r#"{ "instructions": [] }"#).unwrap();
+        assert_eq!(Endianness::from(old.endian), Endianness::Big);
+    }
+}
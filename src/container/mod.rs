@@ -3,13 +3,90 @@ mod gen;
 
 use crate::instructions::OptionalInst;
 
-pub use parser::parse;
+pub use parser::{parse, parse_reachable, parse_located};
 pub use gen::gen_rseq as gen;
-use std::collections::HashMap;
+pub use gen::INSTRUCTIONS_OFFSET;
+use std::collections::{HashMap, BTreeSet};
+use nom::number::Endianness;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RSEQ {
     //pub data: &'a [u8],
     pub instructions: Vec<OptionalInst>,
     //pub labels: HashMap<u32, String>
+    /// Labels that the disassembler made up, which aren't written to LABL.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub generated_labels: BTreeSet<String>
+}
+
+/// The JSON form used by `to-json` and `from-json`: the sequence, plus which way round its numbers go.
+/// Files without `endian` are big-endian.
+#[cfg(feature = "serde")]
+#[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Json {
+    #[serde(default)]
+    pub endian: JsonEndian,
+    #[serde(flatten)]
+    pub rseq: RSEQ
+}
+
+/// nom's `Endianness`, written as `"big"` or `"little"`.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonEndian {
+    Big,
+    Little
+}
+
+#[cfg(feature = "serde")]
+impl Default for JsonEndian {
+    fn default() -> Self {
+        JsonEndian::Big
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<Endianness> for JsonEndian {
+    fn from(endian: Endianness) -> Self {
+        if endian == Endianness::Big { JsonEndian::Big } else { JsonEndian::Little }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<JsonEndian> for Endianness {
+    fn from(endian: JsonEndian) -> Self {
+        match endian {
+            JsonEndian::Big => Endianness::Big,
+            JsonEndian::Little => Endianness::Little
+        }
+    }
+}
+
+/// What a BRSEQ says about itself, apart from the instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub endian: Endianness,
+    pub version: u16,
+    pub file_size: u32,
+    /// Offset and size of the DATA section.
+    pub data: (u32, u32),
+    /// Offset and size of the LABL section.
+    pub labl: (u32, u32),
+    /// Everything in LABL, sorted by address.
+    pub labels: Vec<(u32, String)>
+}
+
+/// A parsed BRSEQ, along with where its instructions came from.
+#[derive(Debug)]
+pub struct Located<'a> {
+    pub rseq: RSEQ,
+    pub header: Header,
+    /// The instructions part of DATA.
+    pub data: &'a [u8],
+    /// Where `data` starts in the file.
+    pub offset: u32,
+    /// Position of each instruction in `data`.
+    pub positions: Vec<u32>
 }
@@ -1,16 +1,17 @@
 use nom::{
     IResult,
+    Offset,
     number::Endianness,
     u32,
     bytes::complete::{tag, take},
-    error::{ParseError, context},
-    combinator::{value, verify, map, map_parser},
+    error::{ParseError, ErrorKind, context},
+    combinator::{value, verify, map, map_opt, map_parser},
     sequence::pair,
     branch::alt,
     multi::{count, length_data}
 };
 
-use super::RSEQ;
+use super::{RSEQ, Located, Header};
 use crate::parse::*;
 use crate::instructions;
 use std::collections::HashMap;
@@ -26,14 +27,14 @@ fn parse_label<'a, E: ParseError<&'a [u8]>>(endian: Endianness) -> impl Fn(&'a [
 
 fn parse_labl_section<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness) -> IResult<&'a [u8], HashMap<u32, String>, E> {
     let (input, _) = tag("LABL")(input)?;
-    let (input, len) = u32!(input, endian)?;
+    let (input, len) = context("Bad section length?", verify(pu32(endian), |&len| len >= 0x8))(input)?;
     let (_rest, relative) = context("Bad section length?", take(len - 0x8))(input)?;
     let (input, cnt) = u32!(input, endian)?;
     let cnt = cnt as usize;
 
 
     let res = {
-        let label_location = map(pu32(endian), |offset| &relative[offset as usize..]);
+        let label_location = map_opt(pu32(endian), |offset| relative.get(offset as usize..));
         map(
             count(map_parser(label_location, parse_label(endian)), cnt),
             HashMap::from_iter
@@ -43,35 +44,70 @@ fn parse_labl_section<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endi
     res
 }
 
-fn parse_data_section<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness, labels: &HashMap<u32, String>) -> IResult<&'a [u8], Vec<instructions::OptionalInst>, E> {
+type Decoder<'a, E> = fn(&'a [u8], Endianness, &HashMap<u32, String>) -> IResult<&'a [u8], instructions::bin::Decoded, E>;
+
+/// Returns the instructions part of DATA, along with what was decoded from it.
+fn parse_data_section<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness, labels: &HashMap<u32, String>, decoder: Decoder<'a, E>) -> IResult<&'a [u8], (&'a [u8], instructions::bin::Decoded), E> {
     let (input, _) = tag("DATA")(input)?;
     let (input, len) = u32!(input, endian)?;
-    let (input, hdrlen) = u32!(input, endian)?;
+    let (input, hdrlen) = context("Bad header length", verify(pu32(endian), |&hdrlen| hdrlen >= 0xC && hdrlen <= len))(input)?;
 
     let (input, _) = take(hdrlen - 0xC)(input)?;
     let (_rest, input) = take(len - hdrlen)(input)?;
 
-    instructions::bin::parse_instructions(input, endian, labels)
+    let (rest, decoded) = decoder(input, endian, labels)?;
+    Ok((rest, (input, decoded)))
 }
 
+/// Parses a BRSEQ, decoding all of DATA as one run of instructions.
 pub fn parse<'a, E: ParseError<&'a [u8]>>(orig_input: &'a [u8]) -> IResult<&'a [u8], RSEQ, E> {
+    map(parse_located(true), |located| located.rseq)(orig_input)
+}
+
+/// Parses a BRSEQ, only decoding the parts of DATA that are reachable as code.
+pub fn parse_reachable<'a, E: ParseError<&'a [u8]>>(orig_input: &'a [u8]) -> IResult<&'a [u8], RSEQ, E> {
+    map(parse_located(false), |located| located.rseq)(orig_input)
+}
+
+/// Parses a BRSEQ, keeping track of where each instruction was decoded from.
+/// `linear` picks between the decoders used by `parse` and `parse_reachable`.
+pub fn parse_located<'a, E: ParseError<&'a [u8]>>(linear: bool) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], Located<'a>, E> {
+    move |orig_input| if linear {
+        parse_with(orig_input, instructions::bin::parse_instructions)
+    } else {
+        parse_with(orig_input, instructions::bin::decode_instructions)
+    }
+}
+
+/// The bytes of a section, given its offset and length.
+fn section<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], (offset, len): (u32, u32)) -> IResult<&'a [u8], &'a [u8], E> {
+    let rest = input.get(offset as usize..).ok_or_else(|| nom::Err::Error(E::from_error_kind(input, ErrorKind::Eof)))?;
+    take(len)(rest)
+}
+
+fn parse_with<'a, E: ParseError<&'a [u8]>>(orig_input: &'a [u8], decoder: Decoder<'a, E>) -> IResult<&'a [u8], Located<'a>, E> {
     let section_header = |endian| move |input| pair(pu32(endian), pu32(endian))(input);
 
     let input = orig_input;
     let (input, _) = tag("RSEQ")(input)?;
     let (input, endian) = bom(input)?;
-    let (input, _) = context("Bad Version", verify(pu16(endian), |&version| version == 0x100))(input)?;
-    let (input, _filesz) = u32!(input, endian)?;
+    let (input, version) = context("Bad Version", verify(pu16(endian), |&version| version == 0x100))(input)?;
+    let (input, file_size) = u32!(input, endian)?;
 
     let (input, _) = context("Unknown header length", verify(pu16(endian), |&hdrlen| hdrlen == 0x20))(input)?;
     let (input, _) = context("Unknown section count", verify(pu16(endian), |&sectcnt| sectcnt == 2))(input)?;
     let (input, data_section) = section_header(endian)(input)?;
     let (_input, labl_section) = section_header(endian)(input)?;
 
-    let data = &orig_input[data_section.0 as usize .. (data_section.0 + data_section.1) as usize];
-    let labl = &orig_input[labl_section.0 as usize .. (labl_section.0 + labl_section.1) as usize];
+    let (_, data) = context("DATA out of bounds", |input| section(input, data_section))(orig_input)?;
+    let (_, labl) = context("LABL out of bounds", |input| section(input, labl_section))(orig_input)?;
     let (_, labels) = parse_labl_section(labl, endian)?;
-    let (_, instructions) = parse_data_section(data, endian, &labels)?;
+    let (_, (data, decoded)) = parse_data_section(data, endian, &labels, decoder)?;
+
+    let mut labels: Vec<(u32, String)> = labels.into_iter().collect();
+    labels.sort();
+    let header = Header { endian, version, file_size, data: data_section, labl: labl_section, labels };
 
-    Ok((&[][..], RSEQ { instructions }))
+    let rseq = RSEQ { instructions: decoded.instructions, generated_labels: decoded.generated };
+    Ok((&[][..], Located { rseq, header, data, offset: orig_input.offset(data) as u32, positions: decoded.positions }))
 }
@@ -0,0 +1,223 @@
+use crate::instructions::{OptionalInst, Instruction, UserOp, Destination};
+use crate::container::RSEQ;
+
+use std::fmt::{self, Display, Formatter, Write};
+
+const BYTES_PER_LINE: usize = 16;
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Destination::Label(l) => write!(f, "{}", l)
+        }
+    }
+}
+
+impl UserOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            UserOp::Set => "=",
+            UserOp::Add => "+=",
+            UserOp::Sub => "-=",
+            UserOp::Mul => "*=",
+            UserOp::Div => "/=",
+            UserOp::Rand => "rand",
+            UserOp::And => "&=",
+            UserOp::Or => "|=",
+            UserOp::Xor => "^=",
+            UserOp::Not => "~=",
+            UserOp::Mod => "%=",
+            UserOp::CmpEq => "==",
+            UserOp::CmpGe => ">=",
+            UserOp::CmpGt => ">",
+            UserOp::CmpLe => "<=",
+            UserOp::CmpLt => "<",
+            UserOp::CmpNe => "!=",
+            UserOp::Shift => "<<=",
+            UserOp::User => "" // special, ends up ignored.
+        }
+    }
+}
+
+impl Instruction {
+    /// Writes the instruction, with `last` in place of its last argument if there is one.
+    fn write(&self, f: &mut Formatter, last: Option<&str>) -> fmt::Result {
+        let last = |value: &dyn Display| last.map_or_else(|| value.to_string(), String::from);
+        match self {
+            Instruction::Note { note, velocity, len } => write!(f, "note {}, {}, {}", note, velocity, last(len)),
+            Instruction::Rest(len) => write!(f, "rest {}", last(len)),
+            Instruction::Instrument(value) => write!(f, "set Instrument = {}", last(value)),
+            Instruction::Fork { track, dest } => write!(f, "fork {}, {}", track, dest),
+            Instruction::Jump(dest) => write!(f, "jump {}", dest),
+            Instruction::Call(dest) => write!(f, "call {}", dest),
+            Instruction::Random { inst, min, max } => inst.write(f, Some(&format!("rand({}, {})", min, max))),
+            Instruction::Variable { inst, var } => inst.write(f, Some(&format!("_{}", var))),
+            Instruction::If => write!(f, "?"),
+            Instruction::LoopStart(count) => write!(f, "start_loop {}", last(count)),
+            Instruction::PrintVar(var) => write!(f, "print _{}", var),
+            Instruction::UserProcess { op: UserOp::User, imm, .. } => write!(f, "process 0x{:x}", *imm as u16),
+            // A negative shift is a right shift.
+            Instruction::UserProcess { op: UserOp::Shift, var, imm } if *imm < 0 && *imm != i16::MIN =>
+                write!(f, "process _{} >>= {}", var, -imm),
+            Instruction::UserProcess { op, var, imm } => write!(f, "process _{} {} {}", var, op.as_str(), last(imm)),
+            Instruction::LoopEnd => write!(f, "end_loop"),
+            Instruction::Return => write!(f, "ret"),
+            Instruction::EndOfTrack => write!(f, "end_track"),
+
+            Instruction::SetU8Param { param, value } => write!(f, "set {:?} = {}", param, last(value)),
+            Instruction::SetU16Param { param, value } => write!(f, "set {:?} = {}", param, last(value)),
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.write(f, None)
+    }
+}
+
+impl Display for OptionalInst {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            OptionalInst::Instruction(i) => i.fmt(f),
+            OptionalInst::Byte(b) => write!(f, ".byte 0x{:02x}", b),
+            OptionalInst::Label(l) => write!(f, "{}:", l)
+        }
+    }
+}
+
+/// Writes runs of unknown bytes as `.bytes` lines.
+pub(crate) fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> fmt::Result {
+    for line in bytes.chunks(BYTES_PER_LINE) {
+        let line: Vec<String> = line.iter().map(|b| format!("0x{:02x}", b)).collect();
+        writeln!(out, ".bytes {}", line.join(", "))?;
+    }
+    Ok(())
+}
+
+/// Writes one instruction per line, putting `?` on the same line as the instruction it guards
+/// and grouping unknown bytes into `.bytes` lines.
+pub fn write_instructions(out: &mut impl Write, instructions: &[OptionalInst]) -> fmt::Result {
+    let mut bytes = Vec::new();
+    for (idx, inst) in instructions.iter().enumerate() {
+        if let OptionalInst::Byte(b) = inst {
+            bytes.push(*b);
+            continue;
+        }
+        write_bytes(out, &bytes)?;
+        bytes.clear();
+
+        match (inst, instructions.get(idx + 1)) {
+            (OptionalInst::Instruction(Instruction::If), Some(OptionalInst::Instruction(_))) => write!(out, "? ")?,
+            _ => writeln!(out, "{}", inst)?
+        }
+    }
+    write_bytes(out, &bytes)
+}
+
+pub fn to_string(rseq: &RSEQ) -> String {
+    let mut out = String::new();
+    write_instructions(&mut out, &rseq.instructions).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::{U8Parameters, U16Parameters, MAX_VARINT};
+    use num_traits::FromPrimitive;
+    use std::path::Path;
+
+    /// A tiny xorshift generator, so the cases are the same every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    /// Any instruction at all, with any arguments its fields can hold. The only exceptions are the ones the
+    /// encoding leaves no room for: notes only go up to 0x7F, varints hold 28 bits, `process 0x...` has no
+    /// variable, and the last argument behind a prefix is always 0.
+    fn arbitrary(rng: &mut Rng) -> Instruction {
+        let u8_params: Vec<U8Parameters> = (0..=255).filter_map(U8Parameters::from_u8).collect();
+        let u16_params: Vec<U16Parameters> = (0..=255).filter_map(U16Parameters::from_u8).collect();
+        let ops: Vec<UserOp> = (0..=255).filter_map(UserOp::from_u8).collect();
+        let byte = rng.next_u64() as u8;
+        let short = rng.next_u64() as i16;
+        let varint = rng.next_u64() & MAX_VARINT;
+        let dest = Destination::Label(format!("label_{}", rng.next_u64() % 4));
+        let pick = rng.next_u64() as usize;
+
+        match rng.next_u64() % 17 {
+            0 => Instruction::Note { note: byte & 0x7F, velocity: rng.next_u64() as u8, len: varint },
+            1 => Instruction::Rest(varint),
+            2 => Instruction::Instrument(varint),
+            3 => Instruction::Fork { track: byte, dest },
+            4 => Instruction::Jump(dest),
+            5 => Instruction::Call(dest),
+            6 => Instruction::If,
+            7 => Instruction::LoopStart(byte),
+            8 => Instruction::PrintVar(byte),
+            9 => Instruction::LoopEnd,
+            10 => Instruction::Return,
+            11 => Instruction::EndOfTrack,
+            12 => Instruction::SetU8Param { param: u8_params[pick % u8_params.len()], value: byte },
+            13 => Instruction::SetU16Param { param: u16_params[pick % u16_params.len()], value: short as u16 },
+            14 => match ops[pick % ops.len()] {
+                UserOp::User => Instruction::UserProcess { op: UserOp::User, var: 0xFF, imm: short },
+                op => Instruction::UserProcess { op, var: byte, imm: short }
+            },
+            prefix => {
+                let inst = std::iter::repeat_with(|| arbitrary(rng)).find(Instruction::can_prefix).unwrap();
+                if prefix == 15 {
+                    Instruction::random(inst, short, rng.next_u64() as i16)
+                } else {
+                    Instruction::variable(inst, byte)
+                }
+            }
+        }
+    }
+
+    fn every_instruction() -> Vec<Instruction> {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        (0..5000).map(|_| arbitrary(&mut rng)).collect()
+    }
+
+    #[test]
+    fn test_instruction_round_trip() {
+        for inst in every_instruction() {
+            let text = inst.to_string();
+            assert_eq!(text.parse::<Instruction>().ok(), Some(inst), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let mut instructions: Vec<OptionalInst> = (0..4).map(|n| OptionalInst::Label(format!("label_{}", n))).collect();
+        for inst in every_instruction() {
+            instructions.push(OptionalInst::Instruction(Instruction::If));
+            instructions.push(OptionalInst::Instruction(inst));
+        }
+        instructions.extend((0..40).map(OptionalInst::Byte));
+        instructions.push(OptionalInst::Instruction(Instruction::If));
+
+        let rseq = RSEQ { instructions, generated_labels: Default::default() };
+        let text = to_string(&rseq);
+        assert_eq!(super::super::assemble(&text, Path::new("")).unwrap().rseq, rseq);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let mut instructions = vec![OptionalInst::Label("some_label".into()), OptionalInst::Byte(0xE5)];
+        instructions.extend(every_instruction().into_iter().map(OptionalInst::Instruction));
+        let rseq = RSEQ { instructions, generated_labels: Default::default() };
+        let json = serde_json::to_string(&rseq).unwrap();
+        assert_eq!(serde_json::from_str::<RSEQ>(&json).unwrap(), rseq);
+    }
+}
@@ -0,0 +1,86 @@
+use super::{Assembly, Span};
+use crate::instructions::bin::Generated;
+
+use std::io::{self, Write};
+
+const BYTES_PER_ROW: usize = 8;
+
+/// Groups consecutive instructions that came from the same source statement,
+/// returning the span and the range of output bytes for each group.
+fn entries<'a>(assembly: &'a Assembly, generated: &'a Generated, len: usize) -> impl Iterator<Item=(&'a Span, usize, usize)> + 'a {
+    let positions = &generated.positions;
+    let mut idx = 0;
+    std::iter::from_fn(move || {
+        let span = assembly.spans.get(idx)?;
+        let start = positions[idx] as usize;
+        while assembly.spans.get(idx) == Some(span) {
+            idx += 1;
+        }
+        let end = positions.get(idx).map(|p| *p as usize).unwrap_or(len);
+        Some((span, start, end))
+    })
+}
+
+/// Writes a listing of each source line next to the bytes it produced.
+/// `data` is the output of `gen_instructions`, and `base` is the file offset it was placed at.
+pub fn write_listing(out: &mut impl Write, source: &str, assembly: &Assembly, data: &[u8], generated: &Generated, base: u32) -> io::Result<()> {
+    let lines: Vec<&str> = source.lines().collect();
+    writeln!(out, "{:<8} {:<8} {:<24} {:>6}  source", "data", "file", "bytes", "line")?;
+    for (span, start, end) in entries(assembly, generated, data.len()) {
+        let line = lines.get(span.line - 1).map(|l| l.trim_end()).unwrap_or("");
+        let mut rows = data[start..end].chunks(BYTES_PER_ROW);
+        let hex = |row: Option<&[u8]>| row.unwrap_or(&[]).iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+
+        writeln!(out, "{:06x}   {:06x}   {:<24} {:>6}  {}", start, start as u32 + base, hex(rows.next()), span.line, line)?;
+        for (row_idx, row) in rows.enumerate() {
+            let offset = start + (row_idx + 1) * BYTES_PER_ROW;
+            writeln!(out, "{:06x}   {:06x}   {}", offset, offset as u32 + base, hex(Some(row)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a tab separated source map with one row per instruction, in the same order as `assembly.rseq.instructions`.
+/// Columns are 1-based, to match what editors show.
+pub fn write_source_map(out: &mut impl Write, assembly: &Assembly, generated: &Generated, len: usize, base: u32) -> io::Result<()> {
+    writeln!(out, "data_offset\tfile_offset\tlength\tline\tstart_column\tend_column")?;
+    for (idx, span) in assembly.spans.iter().enumerate() {
+        let start = generated.positions[idx];
+        let end = generated.positions.get(idx + 1).cloned().unwrap_or(len as u32);
+        writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}", start, start + base, end - start, span.line, span.start + 1, span.end + 1)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::bin::gen_instructions_vec;
+    use nom::number::Endianness;
+    use std::path::Path;
+
+    #[test]
+    fn test_listing() {
+        let source = "start:\nnote 60, 100, 48 # a note\n.bytes 1, 2, 3, 4, 5, 6, 7, 8, 9\njump start\n";
+        let assembly = super::super::assemble(source, Path::new("")).unwrap();
+        let (data, generated) = gen_instructions_vec(&assembly.rseq.instructions, Endianness::Big).unwrap();
+
+        let mut listing = Vec::new();
+        write_listing(&mut listing, source, &assembly, &data, &generated, 0x2C).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        let lines: Vec<&str> = listing.lines().skip(1).map(str::trim_end).collect();
+        assert_eq!(lines, [
+            "000000   00002c                                 1  start:",
+            "000000   00002c   3c 64 30                      2  note 60, 100, 48 # a note",
+            "000003   00002f   01 02 03 04 05 06 07 08       3  .bytes 1, 2, 3, 4, 5, 6, 7, 8, 9",
+            "00000b   000037   09",
+            "00000c   000038   89 00 00 00                   4  jump start",
+        ]);
+
+        let mut map = Vec::new();
+        write_source_map(&mut map, &assembly, &generated, data.len(), 0x2C).unwrap();
+        let map = String::from_utf8(map).unwrap();
+        assert_eq!(map.lines().nth(2), Some("0\t44\t3\t2\t1\t17"));
+        assert_eq!(map.lines().count(), 1 + assembly.rseq.instructions.len());
+    }
+}
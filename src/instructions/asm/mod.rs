@@ -1,20 +1,337 @@
-// mod gen;
-//mod parser;
-
-use lalrpop_util::lalrpop_mod;
-use crate::instructions::OptionalInst;
-
-lalrpop_mod!(parser, "/instructions/asm/asm.rs");
-
-pub use parser::FileParser as AsmParser;
-
-// #[cfg(test)]
-// mod test {
-//     use super::*;
-//
-//     #[test]
-//     fn test_number() {
-//         let parser = parser::u16Parser::new();
-//         assert_eq!(parser.parse("0x1234").unwrap(), 0x1234);
-//     }
-// }
+mod gen;
+mod parser;
+pub mod listing;
+
+use crate::instructions::{OptionalInst, Instruction, U16Parameters, Destination};
+use crate::container::RSEQ;
+use parser::{LineParser, Statement};
+use std::collections::{HashMap, HashSet, BTreeSet};
+use std::fmt;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub use gen::{to_string, write_instructions};
+
+/// Number of tracks a sequence can allocate through `TrackUsage`.
+pub const TRACK_COUNT: u8 = 16;
+
+/// Location of something in the assembly source. `line` starts at 1, columns are byte offsets into the line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.start + 1)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[derive(Debug)]
+pub struct Assembly {
+    pub rseq: RSEQ,
+    /// Where each of `rseq.instructions` came from.
+    /// Instructions generated by a directive point at the directive.
+    pub spans: Vec<Span>,
+    pub warnings: Vec<Diagnostic>
+}
+
+fn parse_single(s: &str) -> Result<OptionalInst, Diagnostic> {
+    let error = |start, message| Diagnostic { span: Span { line: 1, start, end: s.len() }, message };
+    match (LineParser { vars: &HashMap::new() }).parse_line(s) {
+        Ok(mut statements) if statements.len() == 1 => match statements.pop() {
+            Some((_, Statement::Inst(inst))) => Ok(inst),
+            _ => Err(error(0, "Expected an instruction".into()))
+        },
+        Ok(_) => Err(error(0, "Expected a single instruction".into())),
+        Err((start, message)) => Err(error(start, message))
+    }
+}
+
+impl FromStr for OptionalInst {
+    type Err = Diagnostic;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_single(s)
+    }
+}
+
+impl FromStr for Instruction {
+    type Err = Diagnostic;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_single(s)? {
+            OptionalInst::Instruction(i) => Ok(i),
+            other => Err(Diagnostic { span: Span { line: 1, start: 0, end: s.len() }, message: format!("'{}' is not an instruction", other) })
+        }
+    }
+}
+
+pub(crate) fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let digits: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits.chunks(2)
+        .map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).ok())
+        .collect()
+}
+
+/// Line-at-a-time assembler. Bad lines are reported and skipped, so every error in a file
+/// can be collected in one go.
+pub struct Assembler {
+    include_dir: PathBuf,
+    line: usize,
+    vars: HashMap<String, u8>,
+    // Pending block of `.track` declarations.
+    block: Vec<(Span, u8, String)>,
+    // The most recently declared track mask, checked against every fork that follows it.
+    mask: Option<u16>,
+    // Size of everything emitted so far, for `.align`.
+    position: u32,
+    instructions: Vec<OptionalInst>,
+    spans: Vec<Span>,
+    errors: Vec<Diagnostic>,
+    warnings: Vec<Diagnostic>
+}
+
+impl Assembler {
+    /// Creates an assembler that resolves `.incbin` paths relative to `include_dir`.
+    pub fn new(include_dir: &Path) -> Assembler {
+        Assembler {
+            include_dir: include_dir.to_owned(),
+            line: 0,
+            vars: HashMap::new(),
+            block: Vec::new(),
+            mask: None,
+            position: 0,
+            instructions: Vec::new(),
+            spans: Vec::new(),
+            errors: Vec::new(),
+            warnings: Vec::new()
+        }
+    }
+
+    fn push(&mut self, inst: OptionalInst, span: Span) {
+        self.position += inst.encoded_len();
+        self.instructions.push(inst);
+        self.spans.push(span);
+    }
+
+    fn error(&mut self, span: Span, message: String) {
+        self.errors.push(Diagnostic { span, message });
+    }
+
+    /// Expands the pending `.track` block into the `TrackUsage` mask and `fork`s that start each track.
+    /// Track 0 is the track executing the block, so declaring it just jumps to its label.
+    fn flush_tracks(&mut self) {
+        if self.block.is_empty() {
+            return;
+        }
+        let mut block = std::mem::take(&mut self.block);
+        block.sort_by_key(|(_, track, _)| *track);
+
+        let mut mask = 0u16;
+        for (span, track, _) in &block {
+            if *track >= TRACK_COUNT {
+                self.error(span.clone(), format!("Track {} is out of range", track));
+            } else if mask & (1 << track) != 0 {
+                self.error(span.clone(), format!("Track {} is declared twice", track));
+            } else {
+                mask |= 1 << track;
+            }
+        }
+        self.mask = Some(mask);
+
+        self.push(OptionalInst::Instruction(Instruction::SetU16Param { param: U16Parameters::TrackUsage, value: mask }), block[0].0.clone());
+        for (span, track, label) in block.iter().filter(|(_, track, _)| *track != 0) {
+            self.push(OptionalInst::Instruction(Instruction::Fork { track: *track, dest: Destination::Label(label.clone()) }), span.clone());
+        }
+        if let Some((span, _, label)) = block.iter().find(|(_, track, _)| *track == 0) {
+            self.push(OptionalInst::Instruction(Instruction::Jump(Destination::Label(label.clone()))), span.clone());
+        }
+    }
+
+    fn statement(&mut self, span: Span, statement: Statement) {
+        if let Statement::Track { track, label } = statement {
+            self.block.push((span, track, label));
+            return;
+        }
+        self.flush_tracks();
+
+        match statement {
+            Statement::Inst(inst) => {
+                match &inst {
+                    OptionalInst::Instruction(Instruction::SetU16Param { param: U16Parameters::TrackUsage, value }) =>
+                        self.mask = Some(*value),
+                    OptionalInst::Instruction(Instruction::Fork { track, .. }) => match self.mask {
+                        Some(mask) if *track >= TRACK_COUNT || mask & (1 << track) == 0 => self.warnings.push(Diagnostic {
+                            span: span.clone(),
+                            message: format!("Fork to track {} which is not in the declared track mask 0x{:04x}", track, mask)
+                        }),
+                        _ => ()
+                    },
+                    _ => ()
+                }
+                self.push(inst, span);
+            },
+            Statement::Var { name, var } => {
+                self.vars.insert(name, var);
+            },
+            Statement::Bytes(bytes) => for b in bytes {
+                self.push(OptionalInst::Byte(b), span.clone());
+            },
+            Statement::Align(0) => self.error(span, "Cannot align to 0 bytes".into()),
+            Statement::Align(amount) => {
+                // Alignment is relative to the start of the instruction data, the same as label addresses.
+                let padding = (amount - self.position % amount) % amount;
+                for _ in 0..padding {
+                    self.push(OptionalInst::Byte(0), span.clone());
+                }
+            },
+            Statement::IncBin(path) => match std::fs::read(self.include_dir.join(&path)) {
+                Ok(bytes) => for b in bytes {
+                    self.push(OptionalInst::Byte(b), span.clone());
+                },
+                Err(err) => self.error(span, format!("Could not include '{}': {}", path, err))
+            },
+            Statement::Track { .. } => unreachable!()
+        }
+    }
+
+    /// Assembles the next line of input.
+    pub fn feed_line(&mut self, line: &str) {
+        self.line += 1;
+        let line_no = self.line;
+        match (LineParser { vars: &self.vars }).parse_line(line) {
+            Ok(statements) => for (columns, statement) in statements {
+                self.statement(Span { line: line_no, start: columns.start, end: columns.end }, statement);
+            },
+            Err((start, message)) => self.error(Span { line: line_no, start, end: line.len() }, message)
+        }
+    }
+
+    /// Finishes assembling, returning every error found if there were any.
+    pub fn finish(mut self) -> Result<Assembly, Vec<Diagnostic>> {
+        self.flush_tracks();
+
+        let labels: HashSet<&String> = self.instructions.iter().filter_map(|inst| match inst {
+            OptionalInst::Label(l) => Some(l),
+            _ => None
+        }).collect();
+        for (inst, span) in self.instructions.iter().zip(&self.spans) {
+            if let OptionalInst::Instruction(Instruction::Fork { dest: Destination::Label(l), .. })
+                | OptionalInst::Instruction(Instruction::Jump(Destination::Label(l)))
+                | OptionalInst::Instruction(Instruction::Call(Destination::Label(l))) = inst {
+                if !labels.contains(l) {
+                    self.errors.push(Diagnostic { span: span.clone(), message: format!("Undefined label '{}'", l) });
+                }
+            }
+        }
+
+        if !self.errors.is_empty() {
+            self.errors.sort_by_key(|e| (e.span.line, e.span.start));
+            return Err(self.errors);
+        }
+        Ok(Assembly { rseq: RSEQ { instructions: self.instructions, generated_labels: BTreeSet::new() }, spans: self.spans, warnings: self.warnings })
+    }
+}
+
+/// Assembles `input`, resolving `.incbin` paths relative to `include_dir`.
+pub fn assemble(input: &str, include_dir: &Path) -> Result<Assembly, Vec<Diagnostic>> {
+    let mut assembler = Assembler::new(include_dir);
+    for line in input.lines() {
+        assembler.feed_line(line);
+    }
+    assembler.finish()
+}
+
+/// Like `assemble`, but reads the input a line at a time.
+pub fn assemble_reader(input: impl BufRead, include_dir: &Path) -> Result<Assembly, Vec<Diagnostic>> {
+    let mut assembler = Assembler::new(include_dir);
+    for line in input.lines() {
+        match line {
+            Ok(line) => assembler.feed_line(&line),
+            Err(err) => {
+                assembler.line += 1;
+                let span = Span { line: assembler.line, start: 0, end: 0 };
+                assembler.error(span, err.to_string());
+                break;
+            }
+        }
+    }
+    assembler.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_named_vars() {
+        let asm = assemble(".var tempo_state = _17\nprocess tempo_state += 2\nprint tempo_state\n", Path::new("")).unwrap();
+        match &asm.rseq.instructions[..] {
+            [OptionalInst::Instruction(Instruction::UserProcess { var: 17, imm: 2, .. }), OptionalInst::Instruction(Instruction::PrintVar(17))] => (),
+            other => panic!("unexpected instructions: {:?}", other)
+        }
+        assert!(assemble("print undeclared\n", Path::new("")).is_err());
+    }
+
+    #[test]
+    fn test_track_block() {
+        let asm = assemble("start:\n.track 0 at main\n.track 3 at bass\nfork 5, bass\nmain:\nend_track\nbass:\nend_track\n", Path::new("")).unwrap();
+        match &asm.rseq.instructions[1..4] {
+            [OptionalInst::Instruction(Instruction::SetU16Param { value: 0b1001, .. }),
+             OptionalInst::Instruction(Instruction::Fork { track: 3, .. }),
+             OptionalInst::Instruction(Instruction::Jump(_))] => (),
+            other => panic!("unexpected prologue: {:?}", other)
+        }
+        assert_eq!(asm.spans[2].line, 3);
+        assert_eq!(asm.warnings.len(), 1);
+        assert_eq!(asm.warnings[0].span.line, 4);
+    }
+
+    #[test]
+    fn test_data_directives() {
+        let asm = assemble("note 60, 100, 48\n.align 8\n.bytes 1, 0x2, 3\n.hex \"dead BEEF\"\n.fill 2, 0xFF\n", Path::new("")).unwrap();
+        let bytes: Vec<u8> = asm.rseq.instructions[1..].iter().map(|inst| match inst {
+            OptionalInst::Byte(b) => *b,
+            other => panic!("unexpected instruction: {:?}", other)
+        }).collect();
+        assert_eq!(bytes, [0, 0, 0, 0, 0, 1, 2, 3, 0xDE, 0xAD, 0xBE, 0xEF, 0xFF, 0xFF]);
+        assert!(assemble(".hex \"abc\"\n", Path::new("")).is_err());
+
+        let errors = assemble("rest 1\n.fill 0xFFFFFFFF, 0\n.align 0x80000000\n", Path::new("")).unwrap_err();
+        let lines: Vec<(usize, usize)> = errors.iter().map(|e| (e.span.line, e.span.start)).collect();
+        assert_eq!(lines, [(2, 6), (3, 7)]);
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let errors = assemble("note 60, 100\nstart: rest 48 # fine\nbogus 1\nset Pan = 300\njump nowhere\n", Path::new("")).unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|e| e.span.line).collect();
+        assert_eq!(lines, [1, 3, 4, 5]);
+        assert_eq!(errors[0].span.start, 12);
+
+        let asm = assemble("start: ? rest 48 # comment\n", Path::new("")).unwrap();
+        let columns: Vec<(usize, usize)> = asm.spans.iter().map(|s| (s.start, s.end)).collect();
+        assert_eq!(columns, [(0, 6), (7, 8), (9, 16)]);
+    }
+}
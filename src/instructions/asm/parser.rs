@@ -1,62 +1,364 @@
 use crate::instructions::{OptionalInst, Instruction, U8Parameters, U16Parameters, UserOp, Destination, VarInt};
 
-use nom::error::{ParseError, context, ErrorKind};
-use nom::{IResult, Err, Offset, InputTakeAtPosition};
+use nom::error::{ParseError, ErrorKind};
+use nom::{IResult, Err, Offset};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_while, take_while1};
+use nom::character::complete::{char, space0, digit1, hex_digit1, oct_digit1};
+use nom::combinator::{map, map_res, opt, not, recognize};
+use nom::sequence::{preceded, delimited, pair, terminated};
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ops::Range;
 
-// TODO: represent byte?
-struct Line {
-    label: Option<String>,
-    conditional: bool,
-    instruction: Option<Instruction>
+/// Parse error carrying a message meant for the person writing the assembly.
+pub(super) struct Error<'a> {
+    input: &'a str,
+    message: Option<String>
 }
 
-fn parse_label(input: &str) -> IResult<&str, &str> {
-    input.split_at_position1_complete(|item| !(item.is_alphanum() || item == '_' || item == '-'))
+impl<'a> ParseError<&'a str> for Error<'a> {
+    fn from_error_kind(input: &'a str, _kind: ErrorKind) -> Self {
+        Error { input, message: None }
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+type Res<'a, T> = IResult<&'a str, T, Error<'a>>;
+
+type Parsed = Vec<(Range<usize>, Statement)>;
+
+pub(super) enum Statement {
+    Inst(OptionalInst),
+    Var { name: String, var: u8 },
+    Track { track: u8, label: String },
+    Bytes(Vec<u8>),
+    Align(u32),
+    IncBin(String)
+}
+
+/// Most bytes that `.fill` and `.align` can add, since offsets into DATA are 24 bits.
+const MAX_DATA_LEN: u32 = 0x100_0000;
+
+fn fail<T>(input: &str, message: String) -> Res<'_, T> {
+    Err(Err::Failure(Error { input, message: Some(message) }))
+}
+
+/// Turns a recoverable error from `f` into a failure saying what was expected.
+fn expect<'a, T>(what: &'static str, f: impl Fn(&'a str) -> Res<'a, T>) -> impl Fn(&'a str) -> Res<'a, T> {
+    move |input| match f(input) {
+        Err(Err::Error(_)) => fail(input, format!("Expected {}", what)),
+        other => other
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn word(input: &str) -> Res<'_, &str> {
+    take_while1(is_word_char)(input)
+}
+
+fn label(input: &str) -> Res<'_, &str> {
+    recognize(pair(
+        take_while1(|c: char| c.is_ascii_alphabetic()),
+        take_while(|c: char| is_word_char(c) || c == '-')
+    ))(input)
+}
+
+fn comma(input: &str) -> Res<'_, char> {
+    expect("','", delimited(space0, char(','), space0))(input)
+}
+
+fn string(input: &str) -> Res<'_, &str> {
+    expect("a string", delimited(char('"'), take_while(|c| c != '"'), char('"')))(input)
+}
+
+fn integer(input: &str) -> Res<'_, i128> {
+    let (input, negative) = map(opt(char('-')), |sign| sign.is_some())(input)?;
+    let (input, value) = terminated(alt((
+        map_res(preceded(tag("0x"), hex_digit1), |d| i128::from_str_radix(d, 16)),
+        map_res(preceded(tag("0o"), oct_digit1), |d| i128::from_str_radix(d, 8)),
+        map_res(preceded(tag("0b"), take_while1(|c| c == '0' || c == '1')), |d| i128::from_str_radix(d, 2)),
+        map_res(digit1, |d: &str| d.parse::<i128>())
+    )), not(take_while1(is_word_char)))(input)?;
+    Ok((input, if negative { -value } else { value }))
+}
+
+fn number<'a, T: TryFrom<i128>>(what: &'static str) -> impl Fn(&'a str) -> Res<'a, T> {
+    move |input| {
+        let (rest, value) = expect(what, integer)(input)?;
+        match T::try_from(value) {
+            Ok(value) => Ok((rest, value)),
+            Err(_) => fail(input, format!("{} {} is out of range", what, value))
+        }
+    }
 }
 
-fn parse_opcode(input: &str) -> IResult<&str, &str> {
-    input.split_at_position1_complete(|item| !(item.is_alphanum() || item == '_'))
+/// Hex immediates cover the whole u16 range, since user process ids are usually written that way.
+fn imm(input: &str) -> Res<'_, i16> {
+    if input.starts_with("0x") {
+        map(number::<u16>("immediate"), |imm| imm as i16)(input)
+    } else {
+        number("immediate")(input)
+    }
 }
 
-fn parse_instruction(input: &str) -> IResult<&str, Instruction> {
-    let (input, op) = parse_opcode(input)?;
-    let (input, _) = space1(input)?;
+fn destination(input: &str) -> Res<'_, Destination> {
+    map(expect("a label", label), |l| Destination::Label(l.into()))(input)
+}
 
+fn user_op(input: &str) -> Res<'_, &str> {
+    // Longest operators first, so that e.g. "<=" isn't read as "<".
+    const OPS: [&str; 19] = [
+        "<<=", ">>=", "==", ">=", "<=", "!=", "+=", "-=", "*=", "/=", "&=", "|=", "^=", "~=", "%=", "=", ">", "<", "rand"
+    ];
+    match OPS.iter().find(|op| input.starts_with(*op)) {
+        Some(op) => Ok((&input[op.len()..], *op)),
+        None => fail(input, "Expected an operator".into())
+    }
+}
+
+fn to_user_op(op: &str) -> UserOp {
     match op {
-        "note" => unimplemented!(),
-        "rest" => unimplemented!(),
-        "fork" => unimplemented!(),
-        "jump" => unimplemented!(),
-        "call" => unimplemented!(),
-        "start_loop" => unimplemented!(),
-        "print" => unimplemented!(),
-        "process" => unimplemented!(),
-        "end_loop" => Ok((input, Instruction::LoopEnd)),
-        "ret" => Ok((input, Instruction::Return)),
-        "end_track" => Ok((input, Instruction::EndOfTrack)),
-        "set" => unimplemented!(),
-        unknown => unimplemented!()
-    }
-}
-
-fn parse_line(input: &str) -> IResult<&str, Line> {
-    let (input, _) = space0(input)?;
-    // "label: "
-    let (input, (label, _, _)) = opt(tuple((parse_label, tag(':'), space0)))(input)?;
-    // '?'
-    let (input, is_opt) = map(opt(tag('?')), |mark| mark.is_some())(input)?;
-
-    // ".byte <blah>"
-    // TODO
-
-    // "instruction args"
-    let (input, instruction) = parse_instruction(input)?;
-
-    let (input, _) = space0(input)?;
-    // "#comment"
-    let (input, _) = opt(tuple((tag('#'), not_line_ending)))(input)?;
-    // eol
-    let (input, _) = line_ending(input)?;
-
-    Ok((input, Line { label, conditional: is_opt, instruction }))
+        "=" => UserOp::Set,
+        "+=" => UserOp::Add,
+        "-=" => UserOp::Sub,
+        "*=" => UserOp::Mul,
+        "/=" => UserOp::Div,
+        "rand" => UserOp::Rand,
+        "&=" => UserOp::And,
+        "|=" => UserOp::Or,
+        "^=" => UserOp::Xor,
+        "~=" => UserOp::Not,
+        "%=" => UserOp::Mod,
+        "==" => UserOp::CmpEq,
+        ">=" => UserOp::CmpGe,
+        ">" => UserOp::CmpGt,
+        "<=" => UserOp::CmpLe,
+        "<" => UserOp::CmpLt,
+        "!=" => UserOp::CmpNe,
+        "<<=" | ">>=" => UserOp::Shift,
+        _ => unreachable!()
+    }
+}
+
+/// The last argument of an instruction, which can be a random number or a variable instead of a value.
+enum Last<T> {
+    Value(T),
+    Random(i16, i16),
+    Var(u8)
+}
+
+impl<T: Default> Last<T> {
+    /// Builds the instruction, behind a prefix if the argument isn't a value.
+    fn build(self, make: impl FnOnce(T) -> Instruction) -> Instruction {
+        match self {
+            Last::Value(value) => make(value),
+            Last::Random(min, max) => Instruction::random(make(T::default()), min, max),
+            Last::Var(var) => Instruction::variable(make(T::default()), var)
+        }
+    }
+}
+
+pub(super) struct LineParser<'v> {
+    pub vars: &'v HashMap<String, u8>
+}
+
+impl<'v> LineParser<'v> {
+    fn var<'a>(&self, input: &'a str) -> Res<'a, u8> {
+        if input.starts_with('_') {
+            preceded(char('_'), number("variable"))(input)
+        } else {
+            let (rest, name) = expect("a variable", label)(input)?;
+            match self.vars.get(name) {
+                Some(var) => Ok((rest, *var)),
+                None => fail(input, format!("Undeclared variable '{}'", name))
+            }
+        }
+    }
+
+    /// Parses a last argument: `rand(min, max)`, a variable, or whatever `value` parses.
+    fn last<'a, T>(&self, input: &'a str, value: impl Fn(&'a str) -> Res<'a, T>) -> Res<'a, Last<T>> {
+        if let Ok((rest, _)) = tag::<_, _, Error>("rand(")(input) {
+            let (rest, min) = preceded(space0, number("minimum"))(rest)?;
+            let (rest, _) = comma(rest)?;
+            let (rest, max) = number("maximum")(rest)?;
+            let (rest, _) = expect("')'", preceded(space0, char(')')))(rest)?;
+            Ok((rest, Last::Random(min, max)))
+        } else if input.starts_with('_') || label(input).is_ok() {
+            map(|i| self.var(i), Last::Var)(input)
+        } else {
+            map(value, Last::Value)(input)
+        }
+    }
+
+    fn process<'a>(&self, input: &'a str) -> Res<'a, Instruction> {
+        if integer(input).is_ok() {
+            return map(imm, |imm| Instruction::UserProcess { op: UserOp::User, var: 0xFF, imm })(input);
+        }
+        let (input, var) = self.var(input)?;
+        let (input, op) = delimited(space0, user_op, space0)(input)?;
+        let (rest, imm) = self.last(input, imm)?;
+        let imm = match imm {
+            Last::Value(imm) if op == ">>=" => Last::Value(imm.wrapping_neg()),
+            _ if op == ">>=" => return fail(input, "Can only shift right by a number, use <<= instead".into()),
+            imm => imm
+        };
+        let op = to_user_op(op);
+        Ok((rest, imm.build(|imm| Instruction::UserProcess { op, var, imm })))
+    }
+
+    fn set<'a>(&self, input: &'a str) -> Res<'a, Instruction> {
+        let (rest, name) = expect("a parameter", word)(input)?;
+        let (rest, _) = expect("'='", delimited(space0, char('='), space0))(rest)?;
+        if name == "Instrument" {
+            return map(|i| self.last(i, number("instrument")), |last| last.build(Instruction::Instrument))(rest);
+        }
+
+        if let Some(param) = (0..=255).filter_map(U8Parameters::from_u8).find(|p| format!("{:?}", p) == name) {
+            map(|i| self.last(i, number("value")), |last| last.build(|value| Instruction::SetU8Param { param, value }))(rest)
+        } else if let Some(param) = (0..=255).filter_map(U16Parameters::from_u8).find(|p| format!("{:?}", p) == name) {
+            map(|i| self.last(i, number("value")), |last| last.build(|value| Instruction::SetU16Param { param, value }))(rest)
+        } else {
+            fail(input, format!("Unknown parameter '{}'", name))
+        }
+    }
+
+    fn instruction<'a>(&self, input: &'a str) -> Res<'a, Instruction> {
+        let (rest, op) = word(input)?;
+        let (rest, _) = space0(rest)?;
+
+        match op {
+            "note" => {
+                let (rest, note) = number("note")(rest)?;
+                let (rest, _) = comma(rest)?;
+                let (rest, velocity) = number("velocity")(rest)?;
+                let (rest, _) = comma(rest)?;
+                let (rest, len) = self.last(rest, number("length"))?;
+                Ok((rest, len.build(|len| Instruction::Note { note, velocity, len })))
+            },
+            "rest" => map(|i| self.last(i, number::<VarInt>("length")), |last| last.build(Instruction::Rest))(rest),
+            "fork" => {
+                let (rest, track) = number("track")(rest)?;
+                let (rest, _) = comma(rest)?;
+                let (rest, dest) = destination(rest)?;
+                Ok((rest, Instruction::Fork { track, dest }))
+            },
+            "jump" => map(destination, Instruction::Jump)(rest),
+            "call" => map(destination, Instruction::Call)(rest),
+            "start_loop" => map(|i| self.last(i, number("loop count")), |last| last.build(Instruction::LoopStart))(rest),
+            "print" => map(|i| self.var(i), Instruction::PrintVar)(rest),
+            "process" => self.process(rest),
+            "end_loop" => Ok((rest, Instruction::LoopEnd)),
+            "ret" => Ok((rest, Instruction::Return)),
+            "end_track" => Ok((rest, Instruction::EndOfTrack)),
+            "set" => self.set(rest),
+            unknown => fail(input, format!("Unknown instruction '{}'", unknown))
+        }
+    }
+
+    fn directive<'a>(&self, input: &'a str) -> Res<'a, Statement> {
+        let (rest, name) = preceded(char('.'), word)(input)?;
+        let (rest, _) = space0(rest)?;
+
+        match name {
+            "byte" => map(number("byte"), |b| Statement::Inst(OptionalInst::Byte(b)))(rest),
+            "bytes" => {
+                let (mut rest, first) = number("byte")(rest)?;
+                let mut bytes = vec![first];
+                while let Ok((after, _)) = delimited(space0, char::<_, Error>(','), space0)(rest) {
+                    let (after, byte) = number("byte")(after)?;
+                    bytes.push(byte);
+                    rest = after;
+                }
+                Ok((rest, Statement::Bytes(bytes)))
+            },
+            "hex" => {
+                let (rest, s) = string(rest)?;
+                match super::parse_hex(s) {
+                    Some(bytes) => Ok((rest, Statement::Bytes(bytes))),
+                    None => fail(input, format!("Invalid hex string \"{}\"", s))
+                }
+            },
+            "fill" => {
+                let (after, count) = number::<u32>("count")(rest)?;
+                if count > MAX_DATA_LEN {
+                    return fail(rest, format!("count {} is more than DATA can hold", count));
+                }
+                let (rest, _) = comma(after)?;
+                let (rest, value) = number("value")(rest)?;
+                Ok((rest, Statement::Bytes(vec![value; count as usize])))
+            },
+            "align" => {
+                let (after, amount) = number::<u32>("alignment")(rest)?;
+                if amount > MAX_DATA_LEN {
+                    return fail(rest, format!("alignment {} is more than DATA can hold", amount));
+                }
+                Ok((after, Statement::Align(amount)))
+            },
+            "incbin" => map(string, |path| Statement::IncBin(path.into()))(rest),
+            "var" => {
+                let (rest, name) = expect("a name", label)(rest)?;
+                let (rest, _) = expect("'='", delimited(space0, char('='), space0))(rest)?;
+                let (rest, var) = expect("a variable", preceded(char('_'), number("variable")))(rest)?;
+                Ok((rest, Statement::Var { name: name.into(), var }))
+            },
+            "track" => {
+                let (rest, track) = number("track")(rest)?;
+                let (rest, _) = expect("'at'", delimited(space0, tag("at"), space0))(rest)?;
+                let (rest, label) = expect("a label", label)(rest)?;
+                Ok((rest, Statement::Track { track, label: label.into() }))
+            },
+            unknown => fail(input, format!("Unknown directive '.{}'", unknown))
+        }
+    }
+
+    fn statement<'a>(&self, input: &'a str) -> Res<'a, Statement> {
+        if input.starts_with('.') {
+            self.directive(input)
+        } else {
+            expect("an instruction", map(|i| self.instruction(i), |i| Statement::Inst(OptionalInst::Instruction(i))))(input)
+        }
+    }
+
+    /// Parses a single line: any number of `label:`s and `?`s, at most one instruction or directive,
+    /// and an optional `#` comment. Returns the column range of each statement,
+    /// or the column and message of the first error.
+    pub fn parse_line(&self, line: &str) -> Result<Parsed, (usize, String)> {
+        let mut statements = Vec::new();
+        let mut input = line;
+        loop {
+            input = input.trim_start();
+            if input.is_empty() || input.starts_with('#') {
+                return Ok(statements);
+            }
+            let start = line.offset(input);
+
+            let result = match statements.last() {
+                Some((_, Statement::Inst(OptionalInst::Label(_)))) | Some((_, Statement::Inst(OptionalInst::Instruction(Instruction::If)))) | None =>
+                    alt((
+                        map(terminated(label, char(':')), |l| Statement::Inst(OptionalInst::Label(l.into()))),
+                        map(char('?'), |_| Statement::Inst(OptionalInst::Instruction(Instruction::If))),
+                        |i| self.statement(i)
+                    ))(input),
+                Some(_) => fail(input, "Expected end of line".into())
+            };
+
+            match result {
+                Ok((rest, statement)) => {
+                    statements.push((start..line.offset(rest), statement));
+                    input = rest;
+                },
+                Err(Err::Error(e)) | Err(Err::Failure(e)) =>
+                    return Err((line.offset(e.input), e.message.unwrap_or_else(|| "Syntax error".into()))),
+                Err(Err::Incomplete(_)) => unreachable!()
+            }
+        }
+    }
 }
@@ -1,7 +1,7 @@
 use crate::instructions::{OptionalInst, Instruction, U8Parameters, U16Parameters, UserOp, Destination, VarInt};
 use crate::gen::*;
 
-use std::io::Write;
+use std::io::{Write, Cursor};
 use std::collections::HashMap;
 use num_traits::ToPrimitive;
 //use std::util::num::Integer;
@@ -13,10 +13,13 @@ use cookie_factory::sequence::{tuple, pair};
 use cookie_factory::combinator::cond;
 use nom::number::Endianness;
 
-fn gen_varint<W: Write>(var: VarInt) -> impl SerializeFn<W> {
+pub(crate) fn varint_len(var: VarInt) -> u32 {
     let sig_bits = (0 as VarInt).leading_zeros() - var.leading_zeros();
-    let mut bytes = (sig_bits + 6) / 7;
-    if bytes == 0 { bytes = 1; }
+    sig_bits.div_ceil(7).max(1)
+}
+
+fn gen_varint<W: Write>(var: VarInt) -> impl SerializeFn<W> {
+    let bytes = varint_len(var);
     all((0..bytes).rev().map(move |idx| {
         be_u8(((var >> (idx*7)) as u8) & 0x7F | if idx != 0 { 0x80 } else { 0 })
     }))
@@ -37,11 +40,22 @@ impl<W: Seek> FakeInto<W> for Result<WriteContext<W>, GenError> {
     }
 }
 
-pub fn gen_instructions<'a, W: Write + Seek>(instructions: &'a Vec<OptionalInst>, endian: Endianness) -> impl Fn(WriteContext<W>) -> Result<(WriteContext<W>, Vec<(u32, String)>), GenError> + 'a {
+/// Where things ended up after generating a list of instructions.
+/// All positions are relative to the start of the instructions.
+#[derive(Debug)]
+pub struct Generated {
+    pub labels: Vec<(u32, String)>,
+    /// Position of each instruction, in the same order as the input.
+    pub positions: Vec<u32>
+}
+
+pub fn gen_instructions<'a, W: Write + Seek>(instructions: &'a Vec<OptionalInst>, endian: Endianness) -> impl Fn(WriteContext<W>) -> Result<(WriteContext<W>, Generated), GenError> + 'a {
     move |mut ctx| {
         let start_pos = ctx.position;
         let mut labels: HashMap<String, (Option<u32>, Vec<Placeholder<W>>)> = HashMap::new();
+        let mut positions = Vec::with_capacity(instructions.len());
         for instruction in instructions {
+            positions.push((ctx.position - start_pos) as u32);
             let (next, label) = gen_optional_inst(instruction, endian)(ctx)?;
             if let Some(info) = label {
                 match info {
@@ -65,10 +79,18 @@ pub fn gen_instructions<'a, W: Write + Seek>(instructions: &'a Vec<OptionalInst>
             Ok((addr.unwrap(), name))
         }).collect::<Result<_, GenError>>()?;
 
-        Ok((outer_ctx.unwrap(), labels))
+        Ok((outer_ctx.unwrap(), Generated { labels, positions }))
     }
 }
 
+/// Generates just the instructions into a buffer, for when the bytes are needed without the rest of a BRSEQ.
+pub fn gen_instructions_vec(instructions: &Vec<OptionalInst>, endian: Endianness) -> Result<(Vec<u8>, Generated), GenError> {
+    let mut data = vec![0; instructions.iter().map(OptionalInst::encoded_len).sum::<u32>() as usize];
+    let ctx = WriteContext::from(Cursor::new(&mut data[..]));
+    let (_, generated) = gen_instructions(instructions, endian)(ctx)?;
+    Ok((data, generated))
+}
+
 fn gen_optional_inst<'a, W: Write + Seek>(inst: &'a OptionalInst, endian: Endianness) -> impl Fn(WriteContext<W>) -> Result<(WriteContext<W>, Option<LabelInfo<W>>), GenError> + 'a {
     move |ctx: WriteContext<W>| match inst {
         OptionalInst::Label(name) => {
@@ -95,12 +117,30 @@ fn gen_optional_inst<'a, W: Write + Seek>(inst: &'a OptionalInst, endian: Endian
                 Instruction::If | Instruction::LoopEnd | Instruction::Return | Instruction::EndOfTrack => Ok((ctx, None)),
                 Instruction::SetU8Param { value, .. } => be_u8(*value)(ctx).conv(),
                 Instruction::SetU16Param { value, .. } => gu16(*value, endian)(ctx).conv(),
+                Instruction::Random { inst, min, max } => {
+                    let ctx = gen_prefixed(inst)(ctx)?;
+                    tuple((gu16(*min as u16, endian), gu16(*max as u16, endian)))(ctx).conv()
+                },
+                Instruction::Variable { inst, var } => {
+                    let ctx = gen_prefixed(inst)(ctx)?;
+                    be_u8(*var)(ctx).conv()
+                },
                 //_ => unimplemented!()
             }
         }
     }
 }
 
+/// An instruction behind a prefix, up to but not including its last argument.
+fn gen_prefixed<W: Write>(inst: &Instruction) -> impl SerializeFn<W> {
+    let head: Vec<u8> = match *inst {
+        Instruction::Note { velocity, .. } => vec![velocity],
+        Instruction::UserProcess { op, var, .. } => vec![op.to_u8().unwrap(), var],
+        _ => vec![]
+    };
+    tuple((be_u8(inst.get_tag()), all(head.into_iter().map(be_u8))))
+}
+
 fn gen_userop<W:Write>(op: UserOp, var: u8, imm: i16, endian: Endianness) -> impl SerializeFn<W> {
     tuple((
         be_u8(op.to_u8().unwrap()),
@@ -1,5 +1,6 @@
 mod gen;
 mod parser;
 
-pub use gen::gen_instructions;
-pub use parser::parse_instructions;
+pub use gen::{gen_instructions, gen_instructions_vec, Generated};
+pub(crate) use gen::varint_len;
+pub use parser::{parse_instructions, decode_instructions, is_generated_label, Decoded};
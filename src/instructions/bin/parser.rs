@@ -9,8 +9,8 @@ use nom::number::complete::be_u8;
 use nom::number::Endianness;
 use nom::combinator::{map, map_opt};
 use num_traits::FromPrimitive;
-use nom::branch::alt;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet};
 
 fn varint<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], VarInt, E> {
     let (rest, (list, last)) = pair(take_till(|c| c & 0x80 == 0), be_u8)(input)?;
@@ -37,15 +37,59 @@ fn parse_userproc<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianne
     }
 }
 
-fn parse_destination<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness, labels: &HashMap<u32, String>) -> IResult<&'a [u8], Destination, E> {
-    map(pu24(endian), |addr| match labels.get(&addr) {
-        Some(label) => Destination::Label(label.to_string()),
-        None => unimplemented!("Currently, there is no way to handle an destination without a label") //Destination::Address(addr)
-    })(input)
+/// An instruction behind a prefix, up to but not including its last argument, which is left as 0.
+fn parse_prefixed<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], Instruction, E> {
+    let (rest, tag) = be_u8(input)?;
+    match tag {
+        note @ 0..=0x7F => map(be_u8, |velocity| Instruction::Note { note, velocity, len: 0 })(rest),
+        0x80 => Ok((rest, Instruction::Rest(0))),
+        0x81 => Ok((rest, Instruction::Instrument(0))),
+        0xD4 => Ok((rest, Instruction::LoopStart(0))),
+        0xF0 => map(
+            pair(map_opt(be_u8, |op| UserOp::from_u8(op).filter(|op| *op != UserOp::User)), be_u8),
+            |(op, var)| Instruction::UserProcess { op, var, imm: 0 }
+        )(rest),
+        _ => match (U8Parameters::from_u8(tag), U16Parameters::from_u8(tag)) {
+            (Some(param), _) => Ok((rest, Instruction::SetU8Param { param, value: 0 })),
+            (_, Some(param)) => Ok((rest, Instruction::SetU16Param { param, value: 0 })),
+            _ => context("Unknown Instruction", |input: &[u8]| Err(Err::Error(ParseError::from_error_kind(&input[..1], ErrorKind::Switch))))(input)
+        }
+    }
 }
 
-fn parse_instr<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness, labels: &HashMap<u32, String>) -> IResult<&'a [u8], Instruction, E> {
-    let destination = |input| parse_destination(input, endian, labels);
+/// Name given to destinations that don't have an entry in LABL.
+fn generated_label(addr: u32) -> String {
+    format!("loc_{:06x}", addr)
+}
+
+/// Whether `name` looks like it was made up by `generated_label` rather than read from LABL.
+pub fn is_generated_label(name: &str) -> bool {
+    name.strip_prefix("loc_").is_some_and(|addr| addr.len() == 6 && addr.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Resolves destination addresses to labels, remembering every address it has seen.
+struct Resolver<'l> {
+    labels: &'l HashMap<u32, String>,
+    targets: RefCell<Vec<u32>>
+}
+
+impl<'l> Resolver<'l> {
+    fn new(labels: &'l HashMap<u32, String>) -> Resolver<'l> {
+        Resolver { labels, targets: RefCell::new(Vec::new()) }
+    }
+
+    fn resolve(&self, addr: u32) -> Destination {
+        self.targets.borrow_mut().push(addr);
+        Destination::Label(self.labels.get(&addr).cloned().unwrap_or_else(|| generated_label(addr)))
+    }
+}
+
+fn parse_destination<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness, resolver: &Resolver) -> IResult<&'a [u8], Destination, E> {
+    map(pu24(endian), |addr| resolver.resolve(addr))(input)
+}
+
+fn parse_instr<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness, resolver: &Resolver) -> IResult<&'a [u8], Instruction, E> {
+    let destination = |input| parse_destination(input, endian, resolver);
 
     let (rest, tag) = be_u8(input)?;
     match tag {
@@ -61,6 +105,11 @@ fn parse_instr<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness,
         0x89 => map(destination, |dest| Instruction::Jump(dest))(rest),
         0x8A => map(destination, |dest| Instruction::Call(dest))(rest),
 
+        0xA0 => map(
+            pair(parse_prefixed, pair(pi16(endian), pi16(endian))),
+            |(inst, (min, max))| Instruction::random(inst, min, max)
+        )(rest),
+        0xA1 => map(pair(parse_prefixed, be_u8), |(inst, var)| Instruction::variable(inst, var))(rest),
         0xA2 => Ok((rest, Instruction::If)),
 
         0xD4 => map(be_u8, |byte| Instruction::LoopStart(byte))(rest),
@@ -115,44 +164,157 @@ fn parse_instr<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness,
 //    ))
 //}
 
-pub fn parse_instructions<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness, labels: &HashMap<u32, String>) -> IResult<&'a [u8], Vec<OptionalInst>, E> {
-    let begin = input;
-
-    let mut out_labels = labels.clone();
-    let instruction = |input| parse_instr(input, endian, labels);
-    let f = alt((
-        map(instruction, OptionalInst::Instruction),
-        map(be_u8, OptionalInst::Byte)
-    ));
-
-    // Inlined many0 so i could also handle the labels
-    let mut acc = Vec::new();
-    let mut i = input.clone();
-    loop {
-        // TODO: what if the label isn't at the beginning of an instruction/byte for some reason?
-        if let Some(label) = out_labels.remove(&(begin.offset(i) as u32)) {
-            acc.push(OptionalInst::Label(label));
+/// What came out of decoding a run of instructions.
+#[derive(Debug)]
+pub struct Decoded {
+    pub instructions: Vec<OptionalInst>,
+    /// Position of each instruction, relative to the start of the input.
+    pub positions: Vec<u32>,
+    /// Labels made up for destinations that LABL doesn't have.
+    pub generated: BTreeSet<String>
+}
+
+/// Turns decoded instructions into the final list: gaps become bytes, and labels are placed
+/// in front of whatever starts at their address. An instruction that a label points into the middle of
+/// is split back up into bytes, so that the label still ends up in the right place.
+fn place_labels(data: &[u8], decoded: BTreeMap<u32, (u32, Instruction)>, labels: &HashMap<u32, String>, targets: &[u32]) -> Decoded {
+    let mut all_labels: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    let mut generated = BTreeSet::new();
+    for (addr, name) in labels {
+        all_labels.entry(*addr).or_default().push(name.clone());
+    }
+    for addr in targets.iter().filter(|addr| !labels.contains_key(addr)) {
+        let names = all_labels.entry(*addr).or_default();
+        if names.is_empty() {
+            names.push(generated_label(*addr));
+            generated.insert(generated_label(*addr));
         }
+    }
 
-        match f(i.clone()) {
-            Err(Err::Error(_)) => return Ok((i, acc)),
-            Err(e) => return Err(e),
-            Ok((i1, o)) => {
-                if i1 == i {
-                    return Err(Err::Error(E::from_error_kind(i, ErrorKind::Many0)));
-                }
+    let mut decoded = decoded;
+    for addr in all_labels.keys() {
+        let containing = decoded.range(..*addr).next_back().map(|(start, (len, _))| (*start, *len));
+        if let Some((start, len)) = containing {
+            if start + len > *addr {
+                decoded.remove(&start);
+            }
+        }
+    }
+
+    let mut out = Decoded { instructions: Vec::new(), positions: Vec::new(), generated };
+    let mut pos = 0u32;
+    let emit = |out: &mut Decoded, inst: OptionalInst, pos: u32| {
+        out.instructions.push(inst);
+        out.positions.push(pos);
+    };
+    let mut emit_labels = |out: &mut Decoded, pos: u32| if let Some(names) = all_labels.get_mut(&pos) {
+        names.sort();
+        for name in names.drain(..) {
+            emit(out, OptionalInst::Label(name), pos);
+        }
+    };
+    while (pos as usize) < data.len() {
+        emit_labels(&mut out, pos);
+        match decoded.remove(&pos) {
+            Some((len, inst)) => {
+                emit(&mut out, OptionalInst::Instruction(inst), pos);
+                pos += len;
+            },
+            None => {
+                emit(&mut out, OptionalInst::Byte(data[pos as usize]), pos);
+                pos += 1;
+            }
+        }
+    }
+    emit_labels(&mut out, pos);
+    out
+}
+
+/// Decodes the whole input as one run of instructions, falling back to a byte whenever an instruction can't be decoded.
+pub fn parse_instructions<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness, labels: &HashMap<u32, String>) -> IResult<&'a [u8], Decoded, E> {
+    let resolver = Resolver::new(labels);
+    let mut decoded = BTreeMap::new();
+    let mut i = input;
+    while !i.is_empty() {
+        match parse_instr::<E>(i, endian, &resolver) {
+            Ok((rest, inst)) => {
+                decoded.insert(input.offset(i) as u32, (i.offset(rest) as u32, inst));
+                i = rest;
+            },
+            Err(Err::Error(_)) | Err(Err::Incomplete(_)) => i = &i[1..],
+            Err(e) => return Err(e)
+        }
+    }
+
+    let targets = resolver.targets.into_inner();
+    Ok((i, place_labels(input, decoded, labels, &targets)))
+}
+
+/// Decodes only the instructions that can be reached from the start of the data or from a label,
+/// following jumps, calls, forks and fall-through. Everything else is left as bytes.
+pub fn decode_instructions<'a, E: ParseError<&'a [u8]>>(input: &'a [u8], endian: Endianness, labels: &HashMap<u32, String>) -> IResult<&'a [u8], Decoded, E> {
+    let resolver = Resolver::new(labels);
+    let mut decoded: BTreeMap<u32, (u32, Instruction)> = BTreeMap::new();
+    let mut destinations: HashMap<u32, u32> = HashMap::new();
+
+    // (address, whether the instruction there is guarded by a preceding `?`)
+    let mut pending: Vec<(u32, bool)> = labels.keys().map(|addr| (*addr, false)).collect();
+    pending.sort_unstable_by(|a, b| b.cmp(a));
+    pending.push((0, false));
+    let mut visited = HashSet::new();
 
-                i = i1;
-                acc.push(o);
+    while let Some((addr, guarded)) = pending.pop() {
+        if addr as usize >= input.len() || !visited.insert((addr, guarded)) {
+            continue;
+        }
+        let (inst, len, target) = match decoded.get(&addr) {
+            Some((len, inst)) => (inst.clone(), *len, destinations.get(&addr).cloned()),
+            None => {
+                let i = &input[addr as usize..];
+                let seen = resolver.targets.borrow().len();
+                let (inst, len) = match parse_instr::<E>(i, endian, &resolver) {
+                    Ok((rest, inst)) => (inst, i.offset(rest) as u32),
+                    Err(Err::Failure(e)) => return Err(Err::Failure(e)),
+                    Err(_) => continue // unknown opcode, leave it as data
+                };
+                let target = resolver.targets.borrow().get(seen).cloned();
+
+                let overlaps_prev = decoded.range(..addr).next_back().map(|(start, (len, _))| start + len > addr).unwrap_or(false);
+                let overlaps_next = decoded.range(addr + 1..addr + len).next().is_some();
+                if overlaps_prev || overlaps_next {
+                    continue;
+                }
+                decoded.insert(addr, (len, inst.clone()));
+                if let Some(target) = target {
+                    destinations.insert(addr, target);
+                }
+                (inst, len, target)
             }
+        };
+
+        let next = addr + len;
+        match &inst {
+            Instruction::If => pending.push((next, true)),
+            Instruction::Jump(_) => {
+                pending.extend(target.map(|t| (t, false)));
+                if guarded { pending.push((next, false)); }
+            },
+            Instruction::Call(_) | Instruction::Fork { .. } => {
+                pending.extend(target.map(|t| (t, false)));
+                pending.push((next, false));
+            },
+            Instruction::Return | Instruction::EndOfTrack => if guarded { pending.push((next, false)); },
+            _ => pending.push((next, false))
         }
     }
+
+    let targets = resolver.targets.into_inner();
+    Ok((&input[input.len()..], place_labels(input, decoded, labels, &targets)))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use nom::error::VerboseError;
 
     #[test]
     fn test_varint() {
@@ -160,4 +322,38 @@ mod test {
         assert_eq!(varint::<()>(&[0x8F, 0x80, 0x00, 0x14]), Ok((&[0x14][..], 0xF << 14)));
         assert!(varint::<()>(&[0x8F, 0x80]).is_err());
     }
+
+    #[test]
+    fn test_decode_skips_data() {
+        let data = [
+            0x88, 0x01, 0x00, 0x00, 0x0B, // fork 1, loc_00000b
+            0x8A, 0x00, 0x00, 0x0F, // call loc_00000f
+            0xFF, // end_track
+            0x12, // data
+            0x80, 0x30, // rest 48
+            0xFF, // end_track
+            0xE5, // data
+            0xA2, 0xFD, // ? ret
+            0xFD // ret
+        ];
+        let labels = [(0, "start".to_string())].iter().cloned().collect();
+        let label = |name: &str| Destination::Label(name.into());
+        let (_, decoded) = decode_instructions::<()>(&data, Endianness::Big, &labels).unwrap();
+        assert_eq!(decoded.positions, [0, 0, 5, 9, 10, 11, 11, 13, 14, 15, 15, 16, 17]);
+        assert_eq!(decoded.instructions, [
+            OptionalInst::Label("start".into()),
+            OptionalInst::Instruction(Instruction::Fork { track: 1, dest: label("loc_00000b") }),
+            OptionalInst::Instruction(Instruction::Call(label("loc_00000f"))),
+            OptionalInst::Instruction(Instruction::EndOfTrack),
+            OptionalInst::Byte(0x12),
+            OptionalInst::Label("loc_00000b".into()),
+            OptionalInst::Instruction(Instruction::Rest(48)),
+            OptionalInst::Instruction(Instruction::EndOfTrack),
+            OptionalInst::Byte(0xE5),
+            OptionalInst::Label("loc_00000f".into()),
+            OptionalInst::Instruction(Instruction::If),
+            OptionalInst::Instruction(Instruction::Return),
+            OptionalInst::Instruction(Instruction::Return),
+        ]);
+    }
 }
@@ -6,12 +6,15 @@ pub mod bin;
 pub mod asm;
 
 type VarInt = u64;
+/// The biggest value a varint can hold, since the hardware reads at most 4 bytes of 7 bits.
+pub const MAX_VARINT: u64 = 0x0FFF_FFFF;
 
 // mostly based on rseq2midi.cpp and Atlas' BRSEQ documentation
 
 //struct PrefixedInstruction
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instruction {
     Note { note: u8, velocity: u8, len: VarInt }, // 0x00 - 0x7F (u8, var)
     Rest(VarInt), // 0x80 (var)
@@ -22,7 +25,13 @@ pub enum Instruction {
     Jump(Destination), // 0x89 (u24)
     Call(Destination), // 0x8A (u24)
     // 0x8B ..= 0x8F unused
-    // 0xA0 ..= 0xA5 command prefixes, leaving these unrepresented for now.
+    // 0xA0 ..= 0xA5 command prefixes.
+    /// The last argument of `inst` is a random number from `min` to `max` instead, so it's always 0 in `inst`.
+    /// `Instruction::random` builds one that way.
+    Random { inst: Box<Instruction>, min: i16, max: i16 }, // 0xA0 (inst without its last argument, i16, i16)
+    /// The last argument of `inst` comes from variable `var` instead, so it's always 0 in `inst`.
+    /// `Instruction::variable` builds one that way.
+    Variable { inst: Box<Instruction>, var: u8 }, // 0xA1 (inst without its last argument, u8)
     If, // 0xA2, this is technically a prefix instruction but for now it can just be a regular instruction.
     // 0xA6 ..= 0xAF unused
     // 0xB3 ..= 0xBF unused
@@ -41,7 +50,7 @@ pub enum Instruction {
 }
 
 impl Instruction {
-    fn get_tag(&self) -> u8 {
+    pub(crate) fn get_tag(&self) -> u8 {
         use Instruction::*;
         match self {
             Note { note, .. } => *note,
@@ -50,6 +59,8 @@ impl Instruction {
             Fork { .. } => 0x88,
             Jump(_) => 0x89,
             Call(_) => 0x8A,
+            Random { .. } => 0xA0,
+            Variable { .. } => 0xA1,
             If => 0xA2,
             LoopStart(_) => 0xD4,
             PrintVar(_) => 0xD6,
@@ -62,9 +73,105 @@ impl Instruction {
             SetU16Param { param, .. } => param.to_u8().unwrap()
         }
     }
+
+    /// Number of bytes this instruction takes up once encoded.
+    pub fn encoded_len(&self) -> u32 {
+        use Instruction::*;
+        use bin::varint_len;
+        match self {
+            Note { len, .. } => 2 + varint_len(*len),
+            Rest(len) | Instrument(len) => 1 + varint_len(*len),
+            Fork { .. } => 5,
+            Jump(_) | Call(_) => 4,
+            If | LoopEnd | Return | EndOfTrack => 1,
+            LoopStart(_) | PrintVar(_) => 2,
+            UserProcess { op: UserOp::User, .. } => 4,
+            UserProcess { .. } => 5,
+            SetU8Param { .. } => 2,
+            SetU16Param { .. } => 3,
+            Random { inst, .. } => 2 + inst.prefixed_len() + 4,
+            Variable { inst, .. } => 2 + inst.prefixed_len() + 1
+        }
+    }
+
+    /// Whether `Random` and `Variable` can wrap this instruction.
+    pub fn can_prefix(&self) -> bool {
+        use Instruction::*;
+        match self {
+            Note { .. } | Rest(_) | Instrument(_) | LoopStart(_) | SetU8Param { .. } | SetU16Param { .. } => true,
+            UserProcess { op, .. } => *op != UserOp::User,
+            _ => false
+        }
+    }
+
+    /// Bytes between the tag and the last argument, for instructions behind a prefix.
+    fn prefixed_len(&self) -> u32 {
+        match self {
+            Instruction::Note { .. } => 1,
+            Instruction::UserProcess { .. } => 2,
+            _ => 0
+        }
+    }
+
+    /// The instruction behind a `Random` or `Variable` prefix, or the instruction itself.
+    pub fn unprefixed(&self) -> &Instruction {
+        match self {
+            Instruction::Random { inst, .. } | Instruction::Variable { inst, .. } => inst,
+            inst => inst
+        }
+    }
+
+    pub fn unprefixed_mut(&mut self) -> &mut Instruction {
+        match self {
+            Instruction::Random { inst, .. } | Instruction::Variable { inst, .. } => inst,
+            inst => inst
+        }
+    }
+
+    /// `inst` behind a random prefix, with its own last argument cleared since the prefix replaces it.
+    pub fn random(inst: Instruction, min: i16, max: i16) -> Instruction {
+        Instruction::Random { inst: Box::new(inst.with_last_arg(0)), min, max }
+    }
+
+    /// `inst` behind a variable prefix, with its own last argument cleared since the prefix replaces it.
+    pub fn variable(inst: Instruction, var: u8) -> Instruction {
+        Instruction::Variable { inst: Box::new(inst.with_last_arg(0)), var }
+    }
+
+    /// The unprefixed instruction with its last argument set to `value`, as a prefix would at runtime.
+    /// Lengths and counts below 0 become 0, and other values wrap.
+    pub fn with_last_arg(&self, value: i16) -> Instruction {
+        let mut inst = self.unprefixed().clone();
+        let length = value.max(0) as VarInt;
+        match &mut inst {
+            Instruction::Note { len, .. } | Instruction::Rest(len) | Instruction::Instrument(len) => *len = length,
+            Instruction::LoopStart(count) => *count = value.max(0) as u8,
+            Instruction::UserProcess { imm, .. } => *imm = value,
+            Instruction::SetU8Param { value: param, .. } => *param = value as u8,
+            Instruction::SetU16Param { value: param, .. } => *param = value as u16,
+            _ => ()
+        }
+        inst
+    }
+
+    /// Where this instruction can send execution, for `fork`, `jump` and `call`.
+    pub fn destination(&self) -> Option<&Destination> {
+        match self {
+            Instruction::Fork { dest, .. } | Instruction::Jump(dest) | Instruction::Call(dest) => Some(dest),
+            _ => None
+        }
+    }
+
+    pub fn destination_mut(&mut self) -> Option<&mut Destination> {
+        match self {
+            Instruction::Fork { dest, .. } | Instruction::Jump(dest) | Instruction::Call(dest) => Some(dest),
+            _ => None
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Destination {
     Label(String),
     // TODO: phase this out.
@@ -87,7 +194,8 @@ impl Destination {
     // }
 }
 
-#[derive(Debug, FromPrimitive, ToPrimitive, Copy, Clone)]
+#[derive(Debug, FromPrimitive, ToPrimitive, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum U8Parameters {
     Timebase = 0xB0,
     EnvHold = 0xB1, // (-1..=127)
@@ -124,7 +232,8 @@ pub enum U8Parameters {
     Damper = 0xDF, // (bool?)
 }
 
-#[derive(Debug, FromPrimitive, ToPrimitive, Copy, Clone)]
+#[derive(Debug, FromPrimitive, ToPrimitive, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum U16Parameters {
     ModDelay = 0xE0,
     Tempo = 0xE1,
@@ -134,6 +243,7 @@ pub enum U16Parameters {
 }
 
 #[derive(Debug, FromPrimitive, ToPrimitive, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum UserOp {
     Set = 0x80,
     Add = 0x81,
@@ -156,9 +266,21 @@ pub enum UserOp {
     User = 0xE0, // special, no u8
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OptionalInst {
     Instruction(Instruction),
     Byte(u8),
     Label(String)
 }
+
+impl OptionalInst {
+    pub fn encoded_len(&self) -> u32 {
+        match self {
+            OptionalInst::Instruction(i) => i.encoded_len(),
+            OptionalInst::Byte(_) => 1,
+            OptionalInst::Label(_) => 0
+        }
+    }
+}
+
@@ -1,5 +1,7 @@
 pub mod instructions;
 pub mod container;
+pub mod analysis;
+pub mod transform;
 
 pub(crate) mod parse;
 pub(crate) mod gen;
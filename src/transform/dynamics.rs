@@ -0,0 +1,195 @@
+use crate::analysis::{Program, tracks::TrackMap};
+use crate::instructions::{OptionalInst, Instruction, U8Parameters};
+use super::Problem;
+
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+/// The loudest a velocity or volume can be.
+pub const MAX_VALUE: u8 = 127;
+/// The parameters that count as volumes.
+pub const VOLUME_PARAMS: [U8Parameters; 3] = [U8Parameters::Volume, U8Parameters::MasterVolume, U8Parameters::Expression];
+
+/// One step of a change to dynamics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    /// Multiplies values by this much.
+    Scale(f64),
+    /// Adds this much to values.
+    Offset(f64),
+    /// Divides how far values go above `threshold` by `ratio`, bringing the loud parts down.
+    Compress { threshold: f64, ratio: f64 },
+    /// Bends values along `127 * (value / 127) ^ gamma`, leaving 0 and 127 where they are.
+    /// Above 1 makes quiet values quieter, and below 1 makes them louder.
+    Curve(f64)
+}
+
+impl Step {
+    fn apply(&self, value: f64) -> f64 {
+        let max = f64::from(MAX_VALUE);
+        match *self {
+            Step::Scale(by) => value * by,
+            Step::Offset(by) => value + by,
+            Step::Compress { threshold, ratio } if value > threshold => threshold + (value - threshold) / ratio,
+            Step::Compress { .. } => value,
+            Step::Curve(gamma) => max * (value.clamp(0.0, max) / max).powf(gamma)
+        }
+    }
+}
+
+/// A change to dynamics, and where to make it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dynamics {
+    /// Applied one after another, with the result rounded and clamped to 0 to 127 at the end.
+    pub steps: Vec<Step>,
+    /// Whether to change note velocities.
+    pub velocity: bool,
+    /// Whether to change `Volume`, `MasterVolume` and `Expression`.
+    pub volume: bool,
+    /// Track numbers, or `None` for every track.
+    pub tracks: Option<BTreeSet<u8>>,
+    /// Indices of the instructions to change, or `None` for all of them.
+    pub range: Option<Range<usize>>
+}
+
+impl Dynamics {
+    /// Runs a value through every step.
+    pub fn apply(&self, value: u8) -> u8 {
+        let value = self.steps.iter().fold(f64::from(value), |value, step| step.apply(value));
+        value.round().clamp(0.0, f64::from(MAX_VALUE)) as u8
+    }
+
+    fn targets(&self, inst: &Instruction) -> bool {
+        match inst.unprefixed() {
+            Instruction::Note { .. } => self.velocity,
+            Instruction::SetU8Param { param, .. } => self.volume && VOLUME_PARAMS.contains(param),
+            _ => false
+        }
+    }
+}
+
+/// The result of `adjust`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Adjusted {
+    pub instructions: Vec<OptionalInst>,
+    /// Values that were left alone because they can't be known until the program runs.
+    pub warnings: Vec<Problem>
+}
+
+/// Changes the velocity of every note and every volume in `program` that's in range and played by
+/// one of the tracks. Notes behind a prefix are changed too, and so are both ends of a `rand(...)` volume.
+///
+/// Code that's shared between tracks can only be changed if every track that runs it is included,
+/// and anywhere it isn't is an error. Code that nothing runs is only changed when every track is.
+pub fn adjust(program: &Program, dynamics: &Dynamics) -> Result<Adjusted, Vec<Problem>> {
+    let map = TrackMap::new(program);
+    let mut instructions = program.instructions.to_vec();
+    let mut warnings = Vec::new();
+    let mut errors = Vec::new();
+
+    for (idx, inst) in instructions.iter_mut().enumerate() {
+        let inst = match inst {
+            OptionalInst::Instruction(inst) if dynamics.targets(inst) => inst,
+            _ => continue
+        };
+        if dynamics.range.as_ref().is_some_and(|range| !range.contains(&idx)) {
+            continue;
+        }
+        if let Some(tracks) = &dynamics.tracks {
+            let owners: BTreeSet<u8> = map.owners[idx].iter().map(|&track| map.tracks[track].number).collect();
+            let included = owners.iter().filter(|number| tracks.contains(number)).count();
+            if included == 0 {
+                continue;
+            }
+            if included < owners.len() {
+                let owners: Vec<String> = owners.iter().map(u8::to_string).collect();
+                errors.push(Problem { idx, message: format!("Shared by tracks {}, but not all of them are being changed", owners.join(", ")) });
+                continue;
+            }
+        }
+
+        let is_param = matches!(inst.unprefixed(), Instruction::SetU8Param { .. });
+        match inst {
+            Instruction::Random { min, max, .. } if is_param => {
+                let (low, high) = (dynamics.apply((*min).clamp(0, 127) as u8), dynamics.apply((*max).clamp(0, 127) as u8));
+                *min = i16::from(low.min(high));
+                *max = i16::from(low.max(high));
+            },
+            Instruction::Variable { .. } if is_param =>
+                warnings.push(Problem { idx, message: "The volume comes from a variable, so it's left alone".into() }),
+            _ => match inst.unprefixed_mut() {
+                Instruction::Note { velocity, .. } => *velocity = dynamics.apply(*velocity),
+                Instruction::SetU8Param { value, .. } => *value = dynamics.apply(*value),
+                _ => ()
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(Adjusted { instructions, warnings })
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::{assemble, to_string};
+    use crate::container::RSEQ;
+    use std::path::Path;
+
+    #[test]
+    fn test_adjust() {
+        let source = "
+            .track 0 at main
+            .track 1 at bass
+            main:
+                set Volume = 100
+                note 60, 100, 48
+                call shared
+            quiet:
+                note 62, 20, rand(12, 24)
+                set Expression = rand(40, 120)
+                set Volume = _3
+                end_track
+            bass:
+                note 36, 127, _2
+                call shared
+                end_track
+            shared:
+                note 40, 64, 48
+                ret
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let mut dynamics = Dynamics {
+            steps: vec![Step::Compress { threshold: 64.0, ratio: 2.0 }, Step::Offset(10.0)],
+            velocity: true,
+            volume: true,
+            tracks: None,
+            range: None
+        };
+        assert_eq!(dynamics.apply(0), 10);
+        assert_eq!(dynamics.apply(127), 106);
+        dynamics.steps.push(Step::Curve(2.0));
+        assert_eq!(dynamics.apply(127), 88);
+
+        dynamics.steps = vec![Step::Scale(0.5)];
+        let adjusted = adjust(&program, &dynamics).unwrap();
+        assert_eq!(adjusted.warnings, [Problem { idx: program.label("quiet").unwrap() + 3, message: "The volume comes from a variable, so it's left alone".into() }]);
+        let text = to_string(&RSEQ { instructions: adjusted.instructions, generated_labels: Default::default() });
+        for line in &["set Volume = 50", "note 60, 50, 48", "note 62, 10, rand(12, 24)", "set Expression = rand(20, 60)", "note 36, 64, _2", "note 40, 32, 48"] {
+            assert!(text.contains(line), "{} in {}", line, text);
+        }
+
+        dynamics.tracks = Some([1].iter().cloned().collect());
+        let errors = adjust(&program, &dynamics).unwrap_err();
+        assert_eq!(errors, [Problem { idx: program.label("shared").unwrap() + 1, message: "Shared by tracks 0, 1, but not all of them are being changed".into() }]);
+
+        dynamics.tracks = Some([0].iter().cloned().collect());
+        dynamics.range = program.label("quiet").zip(program.label("bass")).map(|(from, to)| from..to);
+        let text = to_string(&RSEQ { instructions: adjust(&program, &dynamics).unwrap().instructions, generated_labels: Default::default() });
+        assert!(text.contains("set Volume = 100") && text.contains("note 62, 10, rand(12, 24)"), "{}", text);
+    }
+}
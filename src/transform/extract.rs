@@ -0,0 +1,200 @@
+use crate::analysis::{Program, lint::{stack_depths, CALL_STACK_DEPTH}};
+use crate::instructions::{OptionalInst, Instruction, Destination};
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The longest run of instructions that gets looked for.
+pub const MAX_RUN: usize = 64;
+
+/// How good a run is to move: by the bytes it saves, then the longest, then the earliest.
+type Score = (i64, usize, Reverse<usize>);
+
+/// A subroutine made out of a repeated run of instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subroutine {
+    pub name: String,
+    /// How many instructions it has, not counting the `ret`.
+    pub len: usize,
+    /// How many places call it.
+    pub calls: usize
+}
+
+/// The result of `extract`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extracted {
+    pub instructions: Vec<OptionalInst>,
+    pub subroutines: Vec<Subroutine>,
+    /// The size of the instructions before and after, in bytes.
+    pub before: u32,
+    pub after: u32
+}
+
+/// Whether the instruction can go in a subroutine, which rules out anything that touches the stack or
+/// doesn't carry on to the next instruction.
+fn can_move(inst: &Instruction) -> bool {
+    !matches!(inst.unprefixed(),
+        Instruction::Fork { .. } | Instruction::Jump(_) | Instruction::Call(_) | Instruction::Return
+            | Instruction::EndOfTrack | Instruction::LoopStart(_) | Instruction::LoopEnd)
+}
+
+/// Finds runs of instructions that are repeated, and moves them into subroutines that each copy `call`s instead,
+/// for as long as that makes the program smaller. The runs that save the most bytes are taken first.
+///
+/// Runs only have straight-line code in them, never have a label inside them, and don't start with an instruction
+/// that a `?` guards or end with a `?`. They're only taken from code that has room on the stack for another call,
+/// however the tracks run it, so nothing ever nests deeper than the hardware allows.
+pub fn extract(program: &Program) -> Extracted {
+    let depths = stack_depths(program);
+    let mut free: Vec<bool> = (0..program.len()).map(|idx| match (program.instruction(idx), depths[idx]) {
+        (Some(inst), Some(depth)) => depth < CALL_STACK_DEPTH && can_move(inst),
+        _ => false
+    }).collect();
+
+    // Instructions that are written the same are the same.
+    let mut ids = HashMap::new();
+    let keys: Vec<usize> = program.instructions.iter().map(|inst| {
+        let next = ids.len();
+        *ids.entry(inst.to_string()).or_insert(next)
+    }).collect();
+    let lens: Vec<i64> = program.instructions.iter().map(|inst| i64::from(inst.encoded_len())).collect();
+    let call_len = i64::from(Instruction::Call(Destination::Label(String::new())).encoded_len());
+    let ret_len = i64::from(Instruction::Return.encoded_len());
+
+    let mut names = HashSet::new();
+    let mut subroutines = Vec::new();
+    let mut bodies = Vec::new();
+    // Where each run that's been moved starts, and where it ends and which subroutine it went to.
+    let mut runs = BTreeMap::new();
+    loop {
+        // How many free instructions there are from each one on.
+        let mut free_from = vec![0; program.len() + 1];
+        for idx in (0..program.len()).rev() {
+            free_from[idx] = if free[idx] { free_from[idx + 1] + 1 } else { 0 };
+        }
+
+        let mut best: Option<(Score, Vec<usize>)> = None;
+        for len in 2..=MAX_RUN {
+            let mut found: HashMap<&[usize], Vec<usize>> = HashMap::new();
+            for start in (0..program.len()).filter(|&start| free_from[start] >= len) {
+                if program.is_guarded(start) || matches!(program.instruction(start + len - 1), Some(Instruction::If)) {
+                    continue;
+                }
+                found.entry(&keys[start..start + len]).or_default().push(start);
+            }
+            for starts in found.into_values() {
+                let mut chosen: Vec<usize> = Vec::new();
+                for start in starts {
+                    if chosen.last().is_none_or(|&last| start >= last + len) {
+                        chosen.push(start);
+                    }
+                }
+                if chosen.len() < 2 {
+                    continue;
+                }
+                let bytes: i64 = lens[chosen[0]..chosen[0] + len].iter().sum();
+                let saved = chosen.len() as i64 * (bytes - call_len) - bytes - ret_len;
+                let score = (saved, len, Reverse(chosen[0]));
+                if best.as_ref().is_none_or(|(best, _)| score > *best) {
+                    best = Some((score, chosen));
+                }
+            }
+        }
+
+        let (len, starts) = match best {
+            Some(((saved, len, _), starts)) if saved > 0 => (len, starts),
+            _ => break
+        };
+        let name = (0..).map(|n| format!("sub_{}", n))
+            .find(|name| program.label(name).is_none() && !names.contains(name)).unwrap();
+        names.insert(name.clone());
+        for &start in &starts {
+            free[start..start + len].iter_mut().for_each(|free| *free = false);
+            runs.insert(start, (start + len, subroutines.len()));
+        }
+        bodies.push(program.instructions[starts[0]..starts[0] + len].to_vec());
+        subroutines.push(Subroutine { name, len, calls: starts.len() });
+    }
+
+    let mut instructions = Vec::with_capacity(program.len());
+    let mut idx = 0;
+    while idx < program.len() {
+        match runs.get(&idx) {
+            Some(&(end, sub)) => {
+                instructions.push(OptionalInst::Instruction(Instruction::Call(Destination::Label(subroutines[sub].name.clone()))));
+                idx = end;
+            },
+            None => {
+                instructions.push(program.instructions[idx].clone());
+                idx += 1;
+            }
+        }
+    }
+    // The subroutines go before any zeroes padding out the end.
+    let padding = instructions.iter().rev().take_while(|inst| **inst == OptionalInst::Byte(0)).count();
+    let padding = instructions.split_off(instructions.len() - padding);
+    for (sub, body) in subroutines.iter().zip(bodies) {
+        instructions.push(OptionalInst::Label(sub.name.clone()));
+        instructions.extend(body);
+        instructions.push(OptionalInst::Instruction(Instruction::Return));
+    }
+    instructions.extend(padding);
+
+    let size = |instructions: &[OptionalInst]| instructions.iter().map(OptionalInst::encoded_len).sum();
+    Extracted { before: size(program.instructions), after: size(&instructions), instructions, subroutines }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::analysis::{lint::lint, timing::song_timing, tracks::TrackMap};
+    use crate::instructions::asm::assemble;
+    use std::path::Path;
+
+    #[test]
+    fn test_extract() {
+        let source = "
+            .track 0 at main
+            .track 1 at bass
+            main:
+                note 60, 100, 24
+                note 62, 100, 24
+                ? note 64, 100, 24
+                rest 24
+                call deep
+                note 60, 100, 24
+                note 62, 100, 24
+                ? note 64, 100, 24
+                rest 24
+                end_track
+            bass:
+                note 60, 100, 24
+                note 62, 100, 24
+                ? note 64, 100, 24
+                rest 24
+                end_track
+            deep:
+                start_loop 2
+                call deeper
+                end_loop
+                ret
+            deeper:
+                note 60, 100, 24
+                note 62, 100, 24
+                ? note 64, 100, 24
+                rest 24
+                ret
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let extracted = extract(&program);
+        assert_eq!(extracted.subroutines, [Subroutine { name: "sub_0".into(), len: 5, calls: 3 }]);
+        // Three notes, a `?` and a rest take 12 bytes, three times, for three calls and a subroutine.
+        assert_eq!(extracted.before - extracted.after, 3 * 12 - 3 * 4 - (12 + 1));
+
+        let after = Program::new(&extracted.instructions);
+        assert_eq!(lint(&after), []);
+        let timing = |program: &Program| song_timing(program, &TrackMap::new(program), 0).tracks;
+        assert_eq!(timing(&after), timing(&program));
+    }
+}
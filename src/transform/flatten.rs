@@ -0,0 +1,164 @@
+use crate::analysis::{Program, tracks::TrackMap, timing::{run, MAX_STEPS}, lint::VARIABLE_COUNT};
+use crate::instructions::{OptionalInst, Instruction, Destination, UserOp};
+use super::Problem;
+
+use std::collections::{BTreeSet, HashSet, VecDeque};
+
+/// How to flatten a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flatten {
+    /// What the variables are at the start of each track. Any that aren't given start at 0.
+    pub vars: Vec<i16>,
+    /// Whether tracks that loop forever end with a `jump` back to where they loop, instead of `end_track`.
+    pub loops: bool
+}
+
+/// The result of `flatten`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flattened {
+    pub instructions: Vec<OptionalInst>,
+    /// Places where something random or up to the game decided what the track does.
+    pub warnings: Vec<Problem>
+}
+
+/// Whether the instruction only matters for where the track goes next, which is already decided once it's flattened.
+fn is_flow(inst: &Instruction) -> bool {
+    match inst {
+        Instruction::UserProcess { op, .. } => matches!(op,
+            UserOp::CmpEq | UserOp::CmpGe | UserOp::CmpGt | UserOp::CmpLe | UserOp::CmpLt | UserOp::CmpNe),
+        _ => matches!(inst, Instruction::If | Instruction::Jump(_) | Instruction::Call(_) | Instruction::Return
+            | Instruction::LoopStart(_) | Instruction::LoopEnd | Instruction::EndOfTrack)
+    }
+}
+
+/// Turns every track of every song into straight-line code, by running it the way `song_timing` does: calls are
+/// inlined, loops are unrolled, and each `?` is decided by the variables, which start out as `flatten.vars` says.
+/// Tracks that end get an `end_track`, and tracks that loop forever run up to the point where they'd repeat
+/// themselves and then `jump` back to a `_loop` label (or just end, without `flatten.loops`).
+///
+/// Each track keeps the name of the label it starts at, and `fork`s go to the flattened copies. `rand(...)`s stay
+/// as they are, except where they decide how the track runs, which takes them to be the least they can be.
+/// `_N` prefixes become the value the variable has when they run.
+pub fn flatten(program: &Program, flatten: &Flatten) -> Result<Flattened, Vec<Problem>> {
+    let mut vars = flatten.vars.clone();
+    vars.resize(VARIABLE_COUNT as usize, 0);
+    let name = |entry: usize| program.label_at(entry).map_or_else(|| format!("entry_{}", entry), String::from);
+
+    let mut instructions = Vec::new();
+    let mut warnings = BTreeSet::new();
+    let mut errors = Vec::new();
+    let mut done = HashSet::new();
+    let mut todo: VecDeque<usize> = TrackMap::new(program).songs.into();
+    while let Some(entry) = todo.pop_front() {
+        if !done.insert(entry) {
+            continue;
+        }
+        let mut code = Vec::new();
+        // Where in `code` each instruction that ran went.
+        let mut starts = Vec::new();
+        let result = run(program, entry, &vars, |idx, _, inst| {
+            starts.push(code.len());
+            let original = program.instruction(idx).unwrap();
+            if let Instruction::Random { min, .. } = original {
+                if matches!(inst, Instruction::LoopStart(_) | Instruction::UserProcess { .. }) {
+                    warnings.insert(Problem { idx, message: format!("This depends on a random number, which is taken to be {} here", min) });
+                }
+            }
+            if let Instruction::UserProcess { op: op @ (UserOp::Rand | UserOp::User), var, .. } = inst {
+                let from = if *op == UserOp::Rand { "a random number" } else { "the game" };
+                warnings.insert(Problem { idx, message: format!("_{} comes from {}, which is taken to be 0 here", var, from) });
+            }
+            match inst {
+                Instruction::Fork { track, dest } => match program.target(dest) {
+                    Some(target) => {
+                        todo.push_back(target);
+                        code.push(Instruction::Fork { track: *track, dest: Destination::Label(name(target)) });
+                    },
+                    None => {
+                        warnings.insert(Problem { idx, message: "The track it starts doesn't exist, so it's left out".into() });
+                    }
+                },
+                inst if is_flow(inst) => (),
+                _ if matches!(original, Instruction::Random { .. }) => code.push(original.clone()),
+                inst => code.push(inst.clone())
+            }
+        });
+
+        instructions.push(OptionalInst::Label(name(entry)));
+        let mut code: Vec<OptionalInst> = code.into_iter().map(OptionalInst::Instruction).collect();
+        match result.looped {
+            Some(_) if flatten.loops => {
+                let point = starts.get(result.loop_steps).copied().unwrap_or(code.len());
+                let back = if point == 0 {
+                    name(entry)
+                } else {
+                    let back = (1..).map(|n| if n == 1 { format!("{}_loop", name(entry)) } else { format!("{}_loop_{}", name(entry), n) })
+                        .find(|label| program.label(label).is_none()).unwrap();
+                    code.insert(point, OptionalInst::Label(back.clone()));
+                    back
+                };
+                code.push(OptionalInst::Instruction(Instruction::Jump(Destination::Label(back))));
+            },
+            None if !result.finished => errors.push(Problem {
+                idx: entry,
+                message: format!("The track was still going after {} instructions without repeating itself", MAX_STEPS)
+            }),
+            _ => code.push(OptionalInst::Instruction(Instruction::EndOfTrack))
+        }
+        instructions.extend(code);
+    }
+
+    if errors.is_empty() {
+        Ok(Flattened { instructions, warnings: warnings.into_iter().collect() })
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::{assemble, to_string};
+    use crate::container::RSEQ;
+    use std::path::Path;
+
+    #[test]
+    fn test_flatten() {
+        let source = "
+            main:
+                set TrackUsage = 3
+                fork 1, bass
+                process _0 == 1
+                ? call fill
+                start_loop 2
+                note 60, 100, 24
+                end_loop
+            again:
+                rest 48
+                jump again
+            fill:
+                note 72, 90, rand(6, 12)
+                ret
+            bass:
+                process _0 += 2
+                note 36, 100, _0
+                end_track
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let text = |flattened: Flattened| to_string(&RSEQ { instructions: flattened.instructions, generated_labels: Default::default() });
+
+        let flattened = flatten(&program, &Flatten { vars: vec![1], loops: true }).unwrap();
+        assert_eq!(flattened.warnings, []);
+        assert_eq!(text(flattened).lines().collect::<Vec<_>>(), [
+            "main:", "set TrackUsage = 3", "fork 1, bass", "note 72, 90, rand(6, 12)", "note 60, 100, 24", "note 60, 100, 24",
+            "main_loop:", "rest 48", "jump main_loop",
+            "bass:", "process _0 += 2", "note 36, 100, 3", "end_track"
+        ]);
+
+        let flattened = flatten(&program, &Flatten { vars: vec![], loops: false }).unwrap();
+        assert_eq!(text(flattened).lines().take(6).collect::<Vec<_>>(), [
+            "main:", "set TrackUsage = 3", "fork 1, bass", "note 60, 100, 24", "note 60, 100, 24", "rest 48"
+        ]);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::analysis::{Program, Flow};
+use crate::instructions::{OptionalInst, Instruction, Destination};
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// One of the sequences to merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part<'a> {
+    pub instructions: &'a [OptionalInst],
+    /// Labels the start of the part if it doesn't have a label already, and goes in front of its other labels
+    /// if `prefix` is set.
+    pub name: String,
+    pub prefix: bool
+}
+
+/// The result of `merge`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Merged {
+    pub instructions: Vec<OptionalInst>,
+    /// Labels that had to be renamed, by index into the parts, with their old and new names.
+    pub renamed: Vec<(usize, String, String)>
+}
+
+/// Without the zeroes padding out the end.
+fn trimmed(instructions: &[OptionalInst]) -> &[OptionalInst] {
+    let padding = instructions.iter().rev().take_while(|inst| **inst == OptionalInst::Byte(0)).count();
+    &instructions[..instructions.len() - padding]
+}
+
+/// Puts several sequences one after another in one list of instructions, so each song can be reached through
+/// its own label. The first part is left exactly as it is, so everything in it stays at the same offset, and
+/// the parts after it get their labels prefixed with their name (if `prefix` is set) and then any label that's
+/// already taken gets a number on the end. A part that doesn't start with a label gets one named after it,
+/// since otherwise nothing could reach it.
+pub fn merge(parts: &[Part]) -> Merged {
+    let mut instructions = Vec::new();
+    let mut renamed = Vec::new();
+    let mut taken: HashSet<String> = HashSet::new();
+
+    for (part_idx, part) in parts.iter().enumerate() {
+        let own = trimmed(part.instructions);
+        let mut names: HashMap<&str, String> = HashMap::new();
+        if part_idx > 0 && !matches!(own.first(), Some(OptionalInst::Label(_))) {
+            instructions.push(OptionalInst::Label(part.name.clone()));
+            names.insert("", part.name.clone());
+        }
+        for inst in own {
+            if let OptionalInst::Label(name) = inst {
+                let wanted = if part_idx > 0 && part.prefix { format!("{}_{}", part.name, name) } else { name.clone() };
+                names.insert(name, wanted);
+            }
+        }
+        // Each name is only given out once, the same way as any other name in the part.
+        let mut names: Vec<(&str, String)> = names.into_iter().collect();
+        names.sort();
+        let mut given = HashMap::new();
+        for (old, wanted) in names {
+            let new = if taken.contains(&wanted) {
+                (2..).map(|n| format!("{}_{}", wanted, n)).find(|name| !taken.contains(name)).unwrap()
+            } else {
+                wanted
+            };
+            if old != new && !old.is_empty() {
+                renamed.push((part_idx, old.to_string(), new.clone()));
+            }
+            taken.insert(new.clone());
+            given.insert(old, new);
+        }
+        if let Some(start) = given.get("") {
+            *instructions.last_mut().unwrap() = OptionalInst::Label(start.clone());
+        }
+
+        instructions.extend(own.iter().map(|inst| {
+            let mut inst = inst.clone();
+            match &mut inst {
+                OptionalInst::Label(name) => *name = given[name.as_str()].clone(),
+                OptionalInst::Instruction(inst) => if let Some(Destination::Label(name)) = inst.destination_mut() {
+                    if let Some(new) = given.get(name.as_str()) {
+                        *name = new.clone();
+                    }
+                },
+                OptionalInst::Byte(_) => ()
+            }
+            inst
+        }));
+    }
+    Merged { instructions, renamed }
+}
+
+/// Pulls the song that starts at `label` out on its own, with everything its tracks can run and nothing else.
+/// The code stays in the same order, apart from the stretch with `label` in it, which goes first so that
+/// the song starts at the start. `None` if there's no such label.
+pub fn split(program: &Program, label: &str) -> Option<Vec<OptionalInst>> {
+    let entry = program.label(label)?;
+    let mut reached = vec![false; program.len()];
+    let mut todo = vec![entry];
+    while let Some(idx) = todo.pop() {
+        if std::mem::replace(&mut reached[idx], true) {
+            continue;
+        }
+        for flow in program.successors(idx) {
+            match flow {
+                Flow::Next(next) | Flow::Jump(next) | Flow::Fork { target: next, .. } => todo.push(next),
+                Flow::Call { target, ret } => todo.extend(&[target, ret]),
+                Flow::Return => ()
+            }
+        }
+    }
+
+    // Stretches of code that are reached. Nothing falls from one into the next, so they can go in any order.
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for idx in (0..program.len()).filter(|&idx| reached[idx]) {
+        match runs.last_mut() {
+            Some(run) if run.end == idx => run.end += 1,
+            _ => runs.push(idx..idx + 1)
+        }
+    }
+    let first = runs.iter().position(|run| run.contains(&entry)).unwrap();
+    let first = runs.remove(first);
+
+    let mut instructions = Vec::new();
+    if first.start != entry {
+        instructions.push(OptionalInst::Instruction(Instruction::Jump(Destination::Label(label.into()))));
+    }
+    for run in std::iter::once(first).chain(runs) {
+        instructions.extend(program.instructions[run].iter().cloned());
+    }
+    Some(instructions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::{assemble, to_string};
+    use crate::container::RSEQ;
+    use std::path::Path;
+
+    fn lines(instructions: Vec<OptionalInst>) -> Vec<String> {
+        to_string(&RSEQ { instructions, generated_labels: Default::default() }).lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_merge_split() {
+        let bank = assemble("song_a:\nnote 60, 100, 48\njump song_a\nshared:\nrest 1\nret\n.fill 4, 0\n", Path::new("")).unwrap().rseq;
+        let new = assemble("set Volume = 100\ncall shared\nend_track\nshared:\nrest 2\nret\n", Path::new("")).unwrap().rseq;
+        let other = assemble("song_a:\nend_track\n", Path::new("")).unwrap().rseq;
+        let parts = [
+            Part { instructions: &bank.instructions, name: "bank".into(), prefix: true },
+            Part { instructions: &new.instructions, name: "song_b".into(), prefix: false },
+            Part { instructions: &other.instructions, name: "other".into(), prefix: true }
+        ];
+        let merged = merge(&parts);
+        assert_eq!(merged.renamed, [(1, "shared".to_string(), "shared_2".to_string()), (2, "song_a".to_string(), "other_song_a".to_string())]);
+        assert_eq!(lines(merged.instructions.clone()), [
+            "song_a:", "note 60, 100, 48", "jump song_a", "shared:", "rest 1", "ret",
+            "song_b:", "set Volume = 100", "call shared_2", "end_track", "shared_2:", "rest 2", "ret",
+            "other_song_a:", "end_track"
+        ]);
+
+        let program = Program::new(&merged.instructions);
+        assert_eq!(lines(split(&program, "song_b").unwrap()), [
+            "song_b:", "set Volume = 100", "call shared_2", "end_track", "shared_2:", "rest 2", "ret"
+        ]);
+        assert_eq!(lines(split(&program, "shared_2").unwrap()), ["shared_2:", "rest 2", "ret"]);
+        assert_eq!(split(&program, "nowhere"), None);
+    }
+}
@@ -0,0 +1,18 @@
+//! Rewrites of whole programs, producing new lists of instructions.
+
+pub mod tempo;
+pub mod remap;
+pub mod dynamics;
+pub mod quantize;
+pub mod extract;
+pub mod optimize;
+pub mod merge;
+pub mod flatten;
+pub mod tracks;
+
+/// Something wrong with an instruction, by index into the original instructions.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Problem {
+    pub idx: usize,
+    pub message: String
+}
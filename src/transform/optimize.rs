@@ -0,0 +1,182 @@
+use crate::analysis::Program;
+use crate::instructions::{OptionalInst, Instruction, U8Parameters, U16Parameters, UserOp, MAX_VARINT};
+
+use std::collections::HashMap;
+
+/// The tag of the setting the instruction changes, if it's a plain `set` of some track setting.
+/// `TrackUsage` allocates tracks rather than setting anything, `Tempo` belongs to the whole song,
+/// where any other track could change it in between, and `Mute` and `Damper` act on the notes that
+/// are playing when they're set, so none of them count.
+fn setting(inst: &Instruction) -> Option<u8> {
+    match inst {
+        Instruction::SetU16Param { param: U16Parameters::TrackUsage, .. } | Instruction::SetU16Param { param: U16Parameters::Tempo, .. }
+            | Instruction::SetU8Param { param: U8Parameters::Mute, .. } | Instruction::SetU8Param { param: U8Parameters::Damper, .. } => None,
+        Instruction::Instrument(_) | Instruction::SetU8Param { .. } | Instruction::SetU16Param { .. } => Some(inst.get_tag()),
+        _ => None
+    }
+}
+
+fn is_unguarded_rest(out: &[OptionalInst]) -> bool {
+    match out {
+        [.., OptionalInst::Instruction(Instruction::If), OptionalInst::Instruction(Instruction::Rest(_))] => false,
+        [.., OptionalInst::Instruction(Instruction::Rest(_))] => true,
+        _ => false
+    }
+}
+
+/// Goes through the instructions once, returning the better version if anything changed.
+fn pass(instructions: &[OptionalInst]) -> Option<Vec<OptionalInst>> {
+    let program = Program::new(instructions);
+    let mut out: Vec<OptionalInst> = Vec::with_capacity(instructions.len());
+    // What each setting is known to be at this point in the straight-line code.
+    let mut known: HashMap<u8, &Instruction> = HashMap::new();
+    let mut changed = false;
+
+    for (idx, item) in instructions.iter().enumerate() {
+        let inst = match item {
+            OptionalInst::Instruction(inst) => inst,
+            // Anything could jump to a label, and where data is nothing is known.
+            _ => {
+                known.clear();
+                out.push(item.clone());
+                continue;
+            }
+        };
+        let guarded = program.is_guarded(idx);
+        let setting = setting(inst);
+
+        let redundant = match inst {
+            Instruction::Rest(0) => true,
+            // Only labels between it and where it goes.
+            Instruction::Jump(dest) => program.target(dest)
+                .is_some_and(|target| target > idx && (idx + 1..target).all(|idx| program.label_at(idx).is_some())),
+            _ => setting.is_some_and(|setting| known.get(&setting) == Some(&inst))
+        };
+        if redundant {
+            // Whether or not the `?` skips it, nothing happens, so the `?` goes too.
+            if guarded {
+                out.pop();
+            }
+            changed = true;
+            continue;
+        }
+
+        if let (Instruction::Rest(len), false, true) = (inst, guarded, is_unguarded_rest(&out)) {
+            // As long as the total still fits in a varint.
+            if let Some(OptionalInst::Instruction(Instruction::Rest(before))) = out.last_mut() {
+                if *before + len <= MAX_VARINT {
+                    *before += len;
+                    changed = true;
+                    continue;
+                }
+            }
+        }
+
+        if let (Some(setting), false) = (setting, guarded) {
+            // A setting changed again before anything could notice it the first time.
+            let overwritten = out.iter().rev()
+                .take_while(|item| matches!(item, OptionalInst::Instruction(inst) if self::setting(inst).is_some()))
+                .position(|item| matches!(item, OptionalInst::Instruction(inst) if self::setting(inst) == Some(setting)));
+            if let Some(back) = overwritten {
+                let at = out.len() - 1 - back;
+                if !matches!(at.checked_sub(1).map(|before| &out[before]), Some(OptionalInst::Instruction(Instruction::If))) {
+                    out.remove(at);
+                    changed = true;
+                }
+            }
+        }
+
+        match (setting, inst) {
+            (Some(setting), _) if !guarded => { known.insert(setting, inst); },
+            (Some(setting), _) => { known.remove(&setting); },
+            (None, Instruction::Random { inst: prefixed, .. }) | (None, Instruction::Variable { inst: prefixed, .. }) => {
+                if let Some(setting) = self::setting(prefixed) {
+                    known.remove(&setting);
+                }
+            },
+            // Anything could happen in a subroutine or a user callback, and loops go back to somewhere else.
+            (None, Instruction::Call(_)) | (None, Instruction::UserProcess { op: UserOp::User, .. })
+                | (None, Instruction::LoopStart(_)) | (None, Instruction::LoopEnd)
+                | (None, Instruction::Jump(_)) | (None, Instruction::Return) | (None, Instruction::EndOfTrack) => known.clear(),
+            _ => ()
+        }
+        out.push(item.clone());
+    }
+    Some(out).filter(|_| changed)
+}
+
+/// Removes instructions that don't do anything and merges ones that can be combined, until there's nothing left to do:
+/// - `rest 0`,
+/// - `jump`s to the instruction right after them,
+/// - `set`s (and `Instrument`s) to what the setting already is, since the last label, call or loop,
+/// - `set`s that get changed again before anything else happens, and
+/// - `rest`s right after each other, which become one as long as the total fits in a varint.
+///
+/// Nothing is merged across a label, since something might jump to it, and anything a `?` guards is only removed
+/// along with the `?`. Lengths are always written as the shortest varint that holds them, so overlong ones in the
+/// original shrink too.
+pub fn optimize(mut instructions: Vec<OptionalInst>) -> Vec<OptionalInst> {
+    while let Some(better) = pass(&instructions) {
+        instructions = better;
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::{assemble, to_string};
+    use crate::container::RSEQ;
+    use std::path::Path;
+
+    #[test]
+    fn test_optimize() {
+        let source = "
+            main:
+                set Volume = 100
+                set Pan = 64
+                set Volume = 90
+                note 60, 100, 48
+                set Volume = 90
+                ? set Pan = 64
+                rest 12
+                rest 0
+                rest 12
+                ? rest 12
+                jump next
+            next:
+                set Volume = 90
+                rest 24
+            again:
+                rest 24
+                call sub
+                set Pan = 64
+                end_track
+            sub:
+                set TrackUsage = 1
+                set TrackUsage = 3
+                set Tempo = 120
+                rest 268435455
+                rest 1
+                set Tempo = 120
+                set Mute = 3
+                note 60, 100, 48
+                set Mute = 3
+                set Mute = 0
+                set Damper = 1
+                set Damper = 1
+                ret
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let optimized = optimize(rseq.instructions);
+        let text = to_string(&RSEQ { instructions: optimized, generated_labels: Default::default() });
+        let lines: Vec<&str> = text.lines().filter(|line| !line.starts_with('.')).collect();
+        assert_eq!(lines, [
+            "main:", "set Pan = 64", "set Volume = 90", "note 60, 100, 48", "rest 24", "? rest 12",
+            "next:", "set Volume = 90", "rest 24", "again:", "rest 24", "call sub", "set Pan = 64", "end_track",
+            "sub:", "set TrackUsage = 1", "set TrackUsage = 3",
+            "set Tempo = 120", "rest 268435455", "rest 1", "set Tempo = 120",
+            "set Mute = 3", "note 60, 100, 48", "set Mute = 3", "set Mute = 0", "set Damper = 1", "set Damper = 1", "ret"
+        ]);
+    }
+}
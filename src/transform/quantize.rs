@@ -0,0 +1,315 @@
+use crate::analysis::{Program, tracks::TrackMap, timing::{song_timeline, DEFAULT_TIMEBASE}};
+use crate::instructions::{OptionalInst, Instruction, U8Parameters};
+use super::Problem;
+
+/// Steps to line lengths up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grid {
+    /// A fixed number of ticks.
+    Ticks(u64),
+    /// This many steps to a whole note, like 16 for sixteenth notes, so it follows `Timebase`.
+    PerWhole(u64)
+}
+
+impl Grid {
+    /// How many ticks a step is at `timebase`, which is always at least 1.
+    pub fn ticks(&self, timebase: u8) -> u64 {
+        let ticks = match *self {
+            Grid::Ticks(ticks) => ticks,
+            // A whole note is 4 quarter notes.
+            Grid::PerWhole(steps) => u64::from(timebase) * 4 / steps.max(1)
+        };
+        ticks.max(1)
+    }
+}
+
+/// Waits that happen one after another with nothing in between that could change how long they take
+/// or skip them, so time can be moved between them without changing their total.
+struct Segment {
+    /// The song-wide tick the first wait starts at, the first time it runs, or `None` if no track runs it.
+    start: Option<u64>,
+    timebase: u8,
+    /// The `rest`s, and notes with note wait on, in order.
+    waits: Vec<usize>
+}
+
+/// Splits the waits in `program` into segments, along with the notes that don't wait, whose length is only
+/// how long they sound, and the `Timebase` they're played at.
+fn segments(program: &Program) -> (Vec<Segment>, Vec<(usize, u8)>) {
+    let map = TrackMap::new(program);
+    let mut ticks = vec![None; program.len()];
+    for song in 0..map.songs.len() {
+        for (tick, runs) in ticks.iter_mut().zip(song_timeline(program, &map, song).0) {
+            *tick = tick.or(runs.first().copied());
+        }
+    }
+    let mut segments = Vec::new();
+    let mut held = Vec::new();
+    let mut current: Option<Segment> = None;
+    let mut timebase = DEFAULT_TIMEBASE;
+    let mut note_wait = true;
+
+    for (idx, tick) in ticks.iter().enumerate() {
+        if program.label_at(idx).is_some() || (idx > 0 && !program.falls_through(idx - 1)) {
+            segments.extend(current.take());
+        }
+        let inst = match program.instruction(idx) {
+            Some(inst) => inst,
+            None => continue
+        };
+        let waits = match inst {
+            Instruction::Rest(_) | Instruction::Note { .. } if program.is_guarded(idx) => {
+                segments.extend(current.take());
+                false
+            },
+            Instruction::Rest(_) => true,
+            Instruction::Note { .. } if note_wait => true,
+            Instruction::Note { .. } => {
+                held.push((idx, timebase));
+                false
+            },
+            Instruction::SetU8Param { param: U8Parameters::Timebase, value } => {
+                segments.extend(current.take());
+                timebase = *value;
+                false
+            },
+            Instruction::SetU8Param { param: U8Parameters::Polyphony, value } => {
+                note_wait = *value != 0;
+                false
+            },
+            // Moving time past a `fork` would move the whole track it starts.
+            Instruction::Call(_) | Instruction::Jump(_) | Instruction::Return | Instruction::EndOfTrack | Instruction::Fork { .. }
+                | Instruction::LoopStart(_) | Instruction::LoopEnd | Instruction::Random { .. } | Instruction::Variable { .. } => {
+                segments.extend(current.take());
+                false
+            },
+            _ => false
+        };
+        if waits {
+            current.get_or_insert_with(|| Segment { start: *tick, timebase, waits: Vec::new() }).waits.push(idx);
+        }
+    }
+    segments.extend(current);
+    (segments, held)
+}
+
+fn length_mut(inst: &mut OptionalInst) -> &mut u64 {
+    match inst {
+        OptionalInst::Instruction(Instruction::Rest(len)) | OptionalInst::Instruction(Instruction::Note { len, .. }) => len,
+        _ => unreachable!("only rests and notes have lengths")
+    }
+}
+
+/// Moves the end of each wait in `segment` to wherever `place` puts it, given where it ends now counting from `start`,
+/// as long as that's not before the wait before it. The last one always ends where it does now, so the total stays the same.
+fn retime(instructions: &mut [OptionalInst], segment: &Segment, start: u64, mut place: impl FnMut(u64) -> u64) {
+    let total = start + segment.waits.iter().map(|&idx| *length_mut(&mut instructions[idx])).sum::<u64>();
+    let mut exact = start;
+    let mut placed = start;
+    for (n, &idx) in segment.waits.iter().enumerate() {
+        let len = length_mut(&mut instructions[idx]);
+        exact += *len;
+        let end = if n + 1 == segment.waits.len() { total } else { place(exact).clamp(placed, total) };
+        *len = end - placed;
+        placed = end;
+    }
+}
+
+/// The result of `quantize`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quantized {
+    pub instructions: Vec<OptionalInst>,
+    /// Notes that ended up with no length, and waits that were left alone, by index into the original instructions.
+    pub warnings: Vec<Problem>
+}
+
+/// Lines the starts of notes and rests up to `grid`, by moving where each wait ends to the nearest step.
+/// The last wait before a label, loop, call, fork or anything else that ends a run of straight-line code makes up
+/// the difference, so every track takes exactly as long as it did. Steps count from the start of the song, so
+/// tracks forked part way through line up with the others, and waits no track runs are left alone with a warning.
+/// Notes that don't wait get their length rounded to a step instead, but never down to nothing.
+pub fn quantize(program: &Program, grid: Grid) -> Quantized {
+    let (segments, held) = segments(program);
+    let mut instructions = program.instructions.to_vec();
+    let mut warnings = Vec::new();
+
+    for segment in &segments {
+        let start = match segment.start {
+            Some(start) => start,
+            None => {
+                let message = "No track gets here from the start of a song, so it's not known where the steps fall";
+                warnings.push(Problem { idx: segment.waits[0], message: message.into() });
+                continue;
+            }
+        };
+        let step = grid.ticks(segment.timebase);
+        retime(&mut instructions, segment, start, |exact| (exact + step / 2) / step * step);
+        for &idx in &segment.waits {
+            let vanished = match (program.instruction(idx), &instructions[idx]) {
+                (Some(Instruction::Note { len, .. }), OptionalInst::Instruction(Instruction::Note { len: new, .. })) => *len != 0 && *new == 0,
+                _ => false
+            };
+            if vanished {
+                warnings.push(Problem { idx, message: "The note is less than half a step from the next one, so it quantizes to nothing".into() });
+            }
+        }
+    }
+    for &(idx, timebase) in &held {
+        let step = grid.ticks(timebase);
+        let len = length_mut(&mut instructions[idx]);
+        if *len != 0 {
+            *len = ((*len + step / 2) / step * step).max(step);
+        }
+    }
+    warnings.sort();
+    Quantized { instructions, warnings }
+}
+
+/// A small xorshift random number generator, so that the same seed always humanizes the same way.
+#[derive(Debug, Clone)]
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        // Mixed up with splitmix64 first, since xorshift is stuck at 0 and slow to get going from small seeds.
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        XorShift((z ^ (z >> 31)).max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    /// A number from `-max` to `max`.
+    pub fn offset(&mut self, max: u64) -> i64 {
+        if max == 0 {
+            return 0;
+        }
+        (self.next_u64() % (2 * max + 1)) as i64 - max as i64
+    }
+}
+
+/// How much to humanize by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Humanize {
+    pub seed: u64,
+    /// The most ticks a note can move by.
+    pub timing: u64,
+    /// The most a velocity can change by.
+    pub velocity: u8
+}
+
+/// Moves notes and rests by up to `timing` ticks and changes velocities by up to `velocity`, at random.
+/// Like `quantize`, the last wait in each run of straight-line code makes up the difference, so tracks take
+/// exactly as long as they did, and nothing moves past the note before it. Velocities stay above 0.
+pub fn humanize(program: &Program, humanize: &Humanize) -> Vec<OptionalInst> {
+    let (segments, held) = segments(program);
+    let mut instructions = program.instructions.to_vec();
+    let mut rng = XorShift::new(humanize.seed);
+
+    for segment in &segments {
+        retime(&mut instructions, segment, 0, |exact| (exact as i64 + rng.offset(humanize.timing)).max(0) as u64);
+    }
+    for &(idx, _) in &held {
+        let len = length_mut(&mut instructions[idx]);
+        if *len != 0 {
+            *len = (*len as i64 + rng.offset(humanize.timing)).max(1) as u64;
+        }
+    }
+    for inst in &mut instructions {
+        if let OptionalInst::Instruction(inst) = inst {
+            if let Instruction::Note { velocity, .. } = inst.unprefixed_mut() {
+                if *velocity != 0 {
+                    let offset = rng.offset(u64::from(humanize.velocity));
+                    *velocity = (i64::from(*velocity) + offset).clamp(1, 127) as u8;
+                }
+            }
+        }
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::assemble;
+    use std::path::Path;
+
+    fn lengths(instructions: &[OptionalInst]) -> Vec<(u8, u64)> {
+        instructions.iter().filter_map(|inst| match inst {
+            OptionalInst::Instruction(Instruction::Note { velocity, len, .. }) => Some((*velocity, *len)),
+            OptionalInst::Instruction(Instruction::Rest(len)) => Some((0, *len)),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn test_quantize() {
+        let source = "
+            main:
+                note 60, 100, 13
+                note 62, 100, 11
+                rest 25
+                note 64, 100, 47
+                call sub
+                note 65, 100, 3
+                note 67, 100, 6
+                end_track
+            sub:
+                set Polyphony = 0
+                note 48, 100, 7
+                rest 50
+                set Polyphony = 1
+                ret
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let quantized = quantize(&program, Grid::PerWhole(16));
+        assert_eq!(lengths(&quantized.instructions), [
+            (100, 12), (100, 12), (0, 24), (100, 48), (100, 0), (100, 9), (100, 12), (0, 50)
+        ]);
+        assert_eq!(quantized.warnings.len(), 1);
+        assert_eq!(quantized.warnings[0].idx, program.label("main").unwrap() + 6);
+
+        let settings = Humanize { seed: 7, timing: 3, velocity: 10 };
+        let humanized = humanize(&program, &settings);
+        assert_eq!(humanized, humanize(&program, &settings));
+        assert_ne!(humanized, humanize(&program, &Humanize { seed: 8, ..settings }));
+        let (before, after) = (lengths(&rseq.instructions), lengths(&humanized));
+        assert_eq!(before[..4].iter().map(|(_, len)| len).sum::<u64>(), after[..4].iter().map(|(_, len)| len).sum::<u64>());
+        for ((velocity, _), (new, _)) in before.iter().zip(&after) {
+            assert!((i16::from(*velocity) - i16::from(*new)).abs() <= 10);
+        }
+
+        // Track 1 starts 6 ticks in, so its steps are 6 ticks off from its own start.
+        let source = "
+            main:
+                rest 6
+                fork 1, other
+                rest 90
+                process _0 == 1
+                ? jump never
+                end_track
+            other:
+                note 60, 100, 12
+                rest 12
+                end_track
+            never:
+                rest 5
+                end_track
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let quantized = quantize(&program, Grid::Ticks(12));
+        assert_eq!(lengths(&quantized.instructions), [(0, 6), (0, 90), (100, 18), (0, 6), (0, 5)]);
+        assert_eq!(quantized.warnings.len(), 1);
+        assert_eq!(quantized.warnings[0].idx, program.label("never").unwrap() + 1);
+    }
+}
@@ -0,0 +1,388 @@
+use crate::analysis::{Program, Flow, tracks::TrackMap, lint::CALL_STACK_DEPTH};
+use crate::instructions::{OptionalInst, Instruction, UserOp};
+use super::Problem;
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::convert::TryFrom;
+
+/// What happens to a note.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mapping {
+    /// Moves notes up (or down) by this many semitones.
+    Transpose(i16),
+    /// Reflects notes so that the old and new note add up to `sum`.
+    /// 127 swaps 0 and 127, like `invert`, and twice a note mirrors around that note.
+    Mirror { sum: i16 },
+    /// Replaces notes with the ones they map to, leaving notes that aren't in it alone.
+    KeyMap(BTreeMap<u8, u8>)
+}
+
+impl Mapping {
+    fn apply(&self, note: u8) -> i16 {
+        let note = i16::from(note);
+        match self {
+            Mapping::Transpose(by) => note + by,
+            Mapping::Mirror { sum } => sum - note,
+            Mapping::KeyMap(map) => map.get(&(note as u8)).map_or(note, |&to| i16::from(to))
+        }
+    }
+}
+
+/// A mapping, only applied to notes played on the given tracks or with the given instruments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub mapping: Mapping,
+    /// Track numbers, or `None` for any track.
+    pub tracks: Option<BTreeSet<u8>>,
+    /// Values of `Instrument`, or `None` for any instrument, including none at all.
+    pub instruments: Option<BTreeSet<u64>>
+}
+
+impl Rule {
+    fn matches(&self, track: Option<u8>, instrument: Option<u64>) -> bool {
+        let track = self.tracks.as_ref().is_none_or(|tracks| track.is_some_and(|track| tracks.contains(&track)));
+        let instrument = self.instruments.as_ref().is_none_or(|instruments| instrument.is_some_and(|instrument| instruments.contains(&instrument)));
+        track && instrument
+    }
+}
+
+/// What to do with notes that end up outside 0 to 127.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfRange {
+    Clamp,
+    Reject
+}
+
+/// The tracks that run each instruction, along with the `Instrument` they've set by then, if any.
+fn contexts(program: &Program) -> Vec<BTreeSet<(Option<u8>, Option<u64>)>> {
+    let map = TrackMap::new(program);
+    let mut contexts = vec![BTreeSet::new(); program.len()];
+    for track in &map.tracks {
+        let mut seen = HashSet::new();
+        let mut todo = vec![(track.entry, Vec::new(), None)];
+        while let Some((idx, stack, instrument)) = todo.pop() {
+            if !seen.insert((idx, stack.clone(), instrument)) {
+                continue;
+            }
+            contexts[idx].insert((Some(track.number), instrument));
+
+            let after = match program.instruction(idx) {
+                Some(Instruction::Instrument(value)) => Some(*value),
+                // An instrument from a prefix can't be known, so only rules for any instrument apply.
+                Some(inst) if matches!(inst.unprefixed(), Instruction::Instrument(_)) => None,
+                _ => instrument
+            };
+            // If a `?` skips it, the instrument stays the same.
+            if program.is_guarded(idx) && after != instrument {
+                todo.extend(program.successors(idx).into_iter().filter_map(|flow| match flow {
+                    Flow::Next(next) => Some((next, stack.clone(), instrument)),
+                    _ => None
+                }));
+            }
+            for flow in program.successors(idx) {
+                match flow {
+                    Flow::Next(next) | Flow::Jump(next) => todo.push((next, stack.clone(), after)),
+                    Flow::Call { target, ret } if stack.len() < CALL_STACK_DEPTH => {
+                        let mut inner = stack.clone();
+                        inner.push(ret);
+                        todo.push((target, inner, after));
+                    },
+                    Flow::Call { .. } | Flow::Fork { .. } => (),
+                    Flow::Return => {
+                        let mut outer = stack.clone();
+                        if let Some(ret) = outer.pop() {
+                            todo.push((ret, outer, after));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    contexts
+}
+
+/// Remaps every note in `program`, using the first rule that matches the track playing it and the
+/// `Instrument` it has set at the time. Notes that no rule matches are left alone.
+///
+/// Code that's shared between tracks, or run with different instruments, can only be remapped if
+/// it comes out the same every way it's run; anywhere it doesn't is an error.
+pub fn remap(program: &Program, rules: &[Rule], out_of_range: OutOfRange) -> Result<Vec<OptionalInst>, Vec<Problem>> {
+    let contexts = contexts(program);
+    let mut instructions = program.instructions.to_vec();
+    let mut errors = Vec::new();
+
+    for (idx, inst) in instructions.iter_mut().enumerate() {
+        let note = match inst {
+            OptionalInst::Instruction(inst) => match inst.unprefixed_mut() {
+                Instruction::Note { note, .. } => note,
+                _ => continue
+            },
+            _ => continue
+        };
+        // Code that nothing runs only gets rules that apply everywhere.
+        let unreached = [(None, None)].iter().cloned().collect();
+        let contexts = if contexts[idx].is_empty() { &unreached } else { &contexts[idx] };
+
+        let mut results = BTreeMap::new();
+        for &(track, instrument) in contexts {
+            let new = rules.iter().find(|rule| rule.matches(track, instrument)).map_or(i16::from(*note), |rule| rule.mapping.apply(*note));
+            results.entry(new).or_insert((track, instrument));
+        }
+        if results.len() > 1 {
+            let ways: Vec<String> = results.iter().map(|(new, (track, instrument))| {
+                let track = track.map_or("no track".to_string(), |track| format!("track {}", track));
+                let instrument = instrument.map_or("no instrument".to_string(), |instrument| format!("instrument {}", instrument));
+                format!("{} on {} with {}", new, track, instrument)
+            }).collect();
+            errors.push(Problem { idx, message: format!("Note {} is shared, and would need to be {}", note, ways.join(", ")) });
+            continue;
+        }
+        let new = *results.keys().next().unwrap();
+        *note = match (new, out_of_range) {
+            (0..=127, _) => new as u8,
+            (_, OutOfRange::Clamp) => new.clamp(0, 127) as u8,
+            (_, OutOfRange::Reject) => {
+                errors.push(Problem { idx, message: format!("Note {} would become {}, outside 0 to 127", note, new) });
+                continue;
+            }
+        };
+    }
+
+    if errors.is_empty() {
+        Ok(instructions)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Reads a key map: one `from to` pair of notes per line, with `#` starting a comment.
+pub fn parse_key_map(text: &str) -> Result<BTreeMap<u8, u8>, String> {
+    let mut map = BTreeMap::new();
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let notes: Vec<&str> = line.split_whitespace().collect();
+        let pair = match notes[..] {
+            [from, to] => from.parse::<u8>().ok().zip(to.parse::<u8>().ok()).filter(|&(from, to)| from <= 127 && to <= 127),
+            _ => None
+        };
+        match pair {
+            Some((from, to)) => if map.insert(from, to).is_some() {
+                return Err(format!("line {}: note {} is mapped twice", line_no + 1, from));
+            },
+            None => return Err(format!("line {}: expected two notes from 0 to 127", line_no + 1))
+        }
+    }
+    Ok(map)
+}
+
+/// Where an instrument goes in another bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NewInstrument {
+    /// The new value of `Instrument`.
+    pub to: u64,
+    /// Semitones to move the notes played with it by, for samples with a different root key.
+    pub transpose: i16
+}
+
+/// Whether the user op compares a variable or sets it to a value, rather than doing arithmetic with it.
+fn sets_or_compares(op: UserOp) -> bool {
+    matches!(op, UserOp::Set | UserOp::CmpEq | UserOp::CmpGe | UserOp::CmpGt | UserOp::CmpLe | UserOp::CmpLt | UserOp::CmpNe)
+}
+
+/// Changes every `Instrument` in `program` to what `table` maps it to, and transposes the notes played with each
+/// instrument, the same way `remap` would. Instruments that aren't in the table are left alone.
+///
+/// A `rand(...)` instrument becomes a new range, which only works if its instruments are still next to each other
+/// afterwards. For an instrument from a variable, it's the variable that changes: every `process _N = value` and
+/// comparison with it gets remapped instead, so it's an error for anything else to change that variable or use it
+/// for something other than `Instrument`. Notes after an instrument from a prefix aren't transposed, since which
+/// instrument it is isn't known.
+pub fn remap_instruments(program: &Program, table: &BTreeMap<u64, NewInstrument>, out_of_range: OutOfRange) -> Result<Vec<OptionalInst>, Vec<Problem>> {
+    let rules: Vec<Rule> = table.iter().filter(|(_, new)| new.transpose != 0).map(|(&from, new)| Rule {
+        mapping: Mapping::Transpose(new.transpose),
+        tracks: None,
+        instruments: Some([from].into())
+    }).collect();
+    let (mut instructions, mut errors) = match remap(program, &rules, out_of_range) {
+        Ok(instructions) => (instructions, Vec::new()),
+        Err(errors) => (program.instructions.to_vec(), errors)
+    };
+    let map = |value: u64| table.get(&value).map_or(value, |new| new.to);
+    let instrument_vars: BTreeSet<u8> = instructions.iter().filter_map(|inst| match inst {
+        OptionalInst::Instruction(Instruction::Variable { inst, var }) if matches!(**inst, Instruction::Instrument(_)) => Some(*var),
+        _ => None
+    }).collect();
+
+    for (idx, inst) in instructions.iter_mut().enumerate() {
+        let inst = match inst {
+            OptionalInst::Instruction(inst) => inst,
+            _ => continue
+        };
+        let error = match inst {
+            Instruction::Instrument(value) => {
+                *value = map(*value);
+                None
+            },
+            Instruction::Random { inst: prefixed, min, max } if matches!(**prefixed, Instruction::Instrument(_)) => {
+                let range = u64::try_from(*min).ok().zip(u64::try_from(*max).ok()).filter(|(min, max)| min <= max);
+                let new = range.and_then(|(from, to)| {
+                    let new_min = map(from);
+                    let follows = (from..=to).all(|value| map(value).checked_sub(value - from) == Some(new_min));
+                    let new_max = new_min.saturating_add(to - from);
+                    Some((i16::try_from(new_min).ok()?, i16::try_from(new_max).ok()?)).filter(|_| follows)
+                });
+                match new {
+                    Some((new_min, new_max)) => {
+                        *min = new_min;
+                        *max = new_max;
+                        None
+                    },
+                    None => Some(format!("Instruments {} to {} don't map to instruments next to each other, so they can't be a random range", min, max))
+                }
+            },
+            Instruction::Variable { inst: prefixed, var } if instrument_vars.contains(var) && !matches!(**prefixed, Instruction::Instrument(_)) =>
+                Some(format!("_{} holds an instrument, so it can't be used for anything else", var)),
+            Instruction::UserProcess { op, var, imm } if instrument_vars.contains(var) => match u64::try_from(*imm) {
+                Ok(value) if sets_or_compares(*op) => match i16::try_from(map(value)) {
+                    Ok(new) => {
+                        *imm = new;
+                        None
+                    },
+                    Err(_) => Some(format!("Instrument {} would become {}, which doesn't fit in a variable", value, map(value)))
+                },
+                _ if sets_or_compares(*op) => None,
+                _ => Some(format!("_{} holds an instrument, so it can only be set or compared", var))
+            },
+            inst => match inst.unprefixed() {
+                Instruction::UserProcess { var, .. } if instrument_vars.contains(var) =>
+                    Some(format!("_{} holds an instrument, so it can't be changed through a prefix", var)),
+                _ => None
+            }
+        };
+        errors.extend(error.map(|message| Problem { idx, message }));
+    }
+
+    if errors.is_empty() {
+        Ok(instructions)
+    } else {
+        errors.sort();
+        Err(errors)
+    }
+}
+
+/// Reads an instrument map: one `from to` pair of instruments per line, optionally followed by the number of
+/// semitones to transpose notes played with it by, with `#` starting a comment.
+pub fn parse_instrument_map(text: &str) -> Result<BTreeMap<u64, NewInstrument>, String> {
+    let mut map = BTreeMap::new();
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (from, to, transpose) = match words[..] {
+            [from, to] => (from, to, "0"),
+            [from, to, transpose] => (from, to, transpose),
+            _ => return Err(format!("line {}: expected two instruments and maybe a transpose", line_no + 1))
+        };
+        let entry = match (from.parse::<u64>(), to.parse::<u64>(), transpose.parse::<i16>()) {
+            (Ok(from), Ok(to), Ok(transpose)) => (from, NewInstrument { to, transpose }),
+            _ => return Err(format!("line {}: expected two instruments and maybe a transpose", line_no + 1))
+        };
+        if map.insert(entry.0, entry.1).is_some() {
+            return Err(format!("line {}: instrument {} is mapped twice", line_no + 1, entry.0));
+        }
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::{assemble, to_string};
+    use crate::container::RSEQ;
+    use std::path::Path;
+
+    fn notes(instructions: &[OptionalInst]) -> Vec<u8> {
+        instructions.iter().filter_map(|inst| match inst {
+            OptionalInst::Instruction(Instruction::Note { note, .. }) => Some(*note),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn test_remap() {
+        let source = "
+            .track 0 at main
+            .track 1 at drums
+            main:
+                set Instrument = 1
+                note 60, 100, 48
+                set Instrument = 2
+                note 120, 100, 48
+                call shared
+                end_track
+            drums:
+                note 36, 100, 48
+                call shared
+                end_track
+            shared:
+                note 40, 100, 48
+                ret
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let keys = parse_key_map("36 38 # kick to snare\n\n40 42").unwrap();
+        let rules = [
+            Rule { mapping: Mapping::KeyMap(keys), tracks: Some([1].iter().cloned().collect()), instruments: None },
+            Rule { mapping: Mapping::Transpose(12), tracks: None, instruments: Some([1, 2].iter().cloned().collect()) },
+        ];
+
+        let errors = remap(&program, &rules, OutOfRange::Clamp).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Note 40 is shared, and would need to be 42 on track 1 with no instrument, 52 on track 0 with instrument 2");
+
+        let everywhere = |mapping| [Rule { mapping, tracks: None, instruments: None }];
+        let remapped = remap(&program, &everywhere(Mapping::Transpose(12)), OutOfRange::Clamp).unwrap();
+        assert_eq!(notes(&remapped), [72, 127, 48, 52]);
+        assert_eq!(remap(&program, &everywhere(Mapping::Transpose(12)), OutOfRange::Reject).unwrap_err().len(), 1);
+        let mirrored = remap(&program, &everywhere(Mapping::Mirror { sum: 127 }), OutOfRange::Reject).unwrap();
+        assert_eq!(notes(&mirrored), [67, 7, 91, 87]);
+        assert!(parse_key_map("1 2 3").is_err());
+    }
+
+    #[test]
+    fn test_remap_instruments() {
+        let source = "
+            main:
+                set Instrument = 1
+                note 60, 100, 48
+                set Instrument = rand(2, 3)
+                note 60, 100, 48
+                process _4 = 5
+                process _4 == 6
+                set Instrument = _4
+                set Instrument = 9
+                end_track
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let table = parse_instrument_map("1 10 -12\n2 20\n3 21\n5 7 # strings\n6 8").unwrap();
+        let remapped = remap_instruments(&program, &table, OutOfRange::Reject).unwrap();
+        assert_eq!(to_string(&RSEQ { instructions: remapped, generated_labels: Default::default() }).lines().collect::<Vec<_>>(), [
+            "main:", "set Instrument = 10", "note 48, 100, 48", "set Instrument = rand(20, 21)", "note 60, 100, 48",
+            "process _4 = 7", "process _4 == 8", "set Instrument = _4", "set Instrument = 9", "end_track"
+        ]);
+
+        let table = parse_instrument_map("3 30").unwrap();
+        let errors = remap_instruments(&program, &table, OutOfRange::Reject).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Instruments 2 to 3 don't map to instruments next to each other, so they can't be a random range");
+        let changed = assemble("process _1 = 2\nprocess _1 += 1\nset Instrument = _1\n", Path::new("")).unwrap().rseq;
+        assert_eq!(remap_instruments(&Program::new(&changed.instructions), &table, OutOfRange::Reject).unwrap_err().len(), 1);
+        assert!(parse_instrument_map("1 2 3 4").is_err());
+    }
+}
@@ -0,0 +1,515 @@
+use crate::analysis::{Program, tracks::TrackMap, timing::{song_timeline, TempoMap, DEFAULT_TEMPO}};
+use crate::instructions::{OptionalInst, Instruction, Destination, U8Parameters, U16Parameters};
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// How many copies of the code after one label can be made for different tempos and rounding
+/// before copies that round a little differently start getting reused.
+pub const MAX_COPIES: usize = 16;
+/// Loops that run at most this many times are unrolled if rounding means each time round
+/// has to be a slightly different length.
+pub const MAX_UNROLL: u8 = 16;
+
+/// The result of `convert`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Converted {
+    pub instructions: Vec<OptionalInst>,
+    /// Places where the conversion might not be exact, by index into the original instructions.
+    pub warnings: Vec<(usize, String)>
+}
+
+/// What decides how the lengths after some point get converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Context {
+    /// Which of the program's songs the track belongs to, since each has its own tempo changes.
+    song: usize,
+    /// The tempo in the original.
+    tempo: u16,
+    /// Rounding carried over from what came before, in `1 / tempo`s of a tick.
+    remainder: u64,
+    note_wait: bool
+}
+
+impl Context {
+    /// How a track starts out, rounding to the nearest tick.
+    fn start(song: usize, tempo: u16) -> Context {
+        Context { song, tempo, remainder: u64::from(tempo / 2), note_wait: true }
+    }
+
+    /// Switches to `tempo`, keeping the rounding carried over.
+    fn retempo(&mut self, tempo: u16) {
+        self.remainder = self.remainder * u64::from(tempo) / u64::from(self.tempo.max(1));
+        self.tempo = tempo;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Walk {
+    Pending,
+    Walking,
+    Done
+}
+
+/// The code from one label up to the next, converted for one context.
+struct SectionCopy {
+    start: usize,
+    context: Context,
+    name: Option<String>,
+    body: Vec<OptionalInst>,
+    walk: Walk,
+    /// The copy it carries on into at the end, if it does.
+    falls_into: Option<usize>,
+    /// Copies it jumps or falls into.
+    next: Vec<usize>,
+    /// Contexts it returns from a subroutine in.
+    returns: Vec<Context>
+}
+
+struct Converter<'p, 'a> {
+    program: &'p Program<'a>,
+    target: u16,
+    /// For each song, the tick each instruction runs at if it only runs once, and the tempo changes of every track.
+    timelines: Vec<(Vec<Option<u64>>, TempoMap)>,
+    copies: Vec<SectionCopy>,
+    keys: HashMap<(usize, Context), usize>,
+    /// The first copy made of each section, which goes where the section was.
+    primary: HashMap<usize, usize>,
+    names: HashSet<String>,
+    warnings: BTreeSet<(usize, String)>
+}
+
+impl<'p, 'a> Converter<'p, 'a> {
+    fn warn(&mut self, idx: usize, message: impl Into<String>) {
+        self.warnings.insert((idx, message.into()));
+    }
+
+    /// Where the section starting at `start` ends: the next label, or the end.
+    fn section_end(&self, start: usize) -> usize {
+        (start + 1..self.program.len()).find(|&idx| self.program.label_at(idx).is_some()).unwrap_or(self.program.len())
+    }
+
+    /// The copy of the section at `start` for `context`, making it if there isn't one yet.
+    fn copy_of(&mut self, start: usize, context: Context) -> usize {
+        if let Some(&id) = self.keys.get(&(start, context)) {
+            return id;
+        }
+        let count = self.copies.iter().filter(|copy| copy.start == start).count();
+        if count >= MAX_COPIES {
+            let close = self.copies.iter().enumerate()
+                .filter(|(_, copy)| copy.start == start && copy.context.tempo == context.tempo && copy.context.note_wait == context.note_wait)
+                .min_by_key(|(_, copy)| copy.context.remainder.max(context.remainder) - copy.context.remainder.min(context.remainder))
+                .map(|(id, _)| id);
+            if let Some(id) = close {
+                self.warn(start, format!("More than {} copies would be needed, so rounding might be off by more than a tick", MAX_COPIES));
+                return id;
+            }
+        }
+
+        let label = self.program.label_at(start);
+        let name = if count == 0 {
+            label.map(String::from)
+        } else {
+            let base = label.unwrap_or("start");
+            let name = (1..).map(|n| format!("{}_{}", base, n))
+                .find(|name| self.program.label(name).is_none() && !self.names.contains(name)).unwrap();
+            Some(name)
+        };
+        self.names.extend(name.clone());
+        let id = self.copies.len();
+        self.copies.push(SectionCopy { start, context, name, body: Vec::new(), walk: Walk::Pending, falls_into: None, next: Vec::new(), returns: Vec::new() });
+        self.keys.insert((start, context), id);
+        self.primary.entry(start).or_insert(id);
+        id
+    }
+
+    fn destination(&self, id: usize) -> Destination {
+        Destination::Label(self.copies[id].name.clone().expect("only the start has no label"))
+    }
+
+    /// Converts the wait at `idx`, following any tempo changes other tracks make while it waits.
+    fn wait(&self, idx: usize, len: u64, context: &mut Context) -> u64 {
+        let (ticks, tempo) = &self.timelines[context.song];
+        let mut converted = 0;
+        let mut done = 0;
+        if let Some(start) = ticks[idx] {
+            for (tick, new) in tempo.tempos().filter(|&(tick, _)| tick > start && tick < start + len) {
+                converted += self.carry(tick - start - done, context);
+                context.retempo(new);
+                done = tick - start;
+            }
+        }
+        converted + self.carry(len - done, context)
+    }
+
+    /// Converts a length that the track waits for at one tempo, carrying the rounding on.
+    fn carry(&self, len: u64, context: &mut Context) -> u64 {
+        let scaled = len * u64::from(self.target) + context.remainder;
+        let tempo = u64::from(context.tempo.max(1));
+        context.remainder = scaled % tempo;
+        scaled / tempo
+    }
+
+    /// Converts a length on its own, rounding to the nearest tick.
+    fn length(&self, len: u64, context: &Context) -> u64 {
+        let tempo = u64::from(context.tempo.max(1));
+        (len * u64::from(self.target) + tempo / 2) / tempo
+    }
+
+    /// Every context that a call to the copy `id` can return in.
+    fn returns_of(&mut self, id: usize) -> Vec<Context> {
+        let mut seen = HashSet::new();
+        let mut todo = vec![id];
+        let mut returns = Vec::new();
+        while let Some(id) = todo.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            if self.copies[id].walk == Walk::Pending {
+                self.walk(id);
+            }
+            for context in &self.copies[id].returns {
+                if !returns.contains(context) {
+                    returns.push(*context);
+                }
+            }
+            todo.extend(&self.copies[id].next);
+        }
+        returns
+    }
+
+    fn walk(&mut self, id: usize) {
+        self.copies[id].walk = Walk::Walking;
+        let (start, context) = (self.copies[id].start, self.copies[id].context);
+        let end = self.section_end(start);
+        let first = if self.program.label_at(start).is_some() { start + 1 } else { start };
+
+        let mut body = Vec::new();
+        let mut next = Vec::new();
+        let mut returns = Vec::new();
+        let after = self.walk_range(first..end, context, &mut body, &mut next, &mut returns);
+        let falls_into = match after {
+            Some(context) if end < self.program.len() => {
+                let into = self.copy_of(end, context);
+                next.push(into);
+                Some(into)
+            },
+            _ => None
+        };
+
+        let copy = &mut self.copies[id];
+        copy.body = body;
+        copy.next = next;
+        copy.returns = returns;
+        copy.falls_into = falls_into;
+        copy.walk = Walk::Done;
+    }
+
+    /// The `end_loop` that goes with the `start_loop` at `idx`, if it's before `end`.
+    fn loop_end(&self, idx: usize, end: usize) -> Option<usize> {
+        let mut depth = 0;
+        for idx in idx + 1..end {
+            match self.program.instruction(idx).map(Instruction::unprefixed) {
+                Some(Instruction::LoopStart(_)) => depth += 1,
+                Some(Instruction::LoopEnd) if depth == 0 => return Some(idx),
+                Some(Instruction::LoopEnd) => depth -= 1,
+                _ => ()
+            }
+        }
+        None
+    }
+
+    /// Converts `range`, returning the context at the end if it gets there.
+    fn walk_range(&mut self, range: std::ops::Range<usize>, mut context: Context, out: &mut Vec<OptionalInst>, next: &mut Vec<usize>, returns: &mut Vec<Context>) -> Option<Context> {
+        let program = self.program;
+        let mut idx = range.start;
+        while idx < range.end {
+            let guarded = program.is_guarded(idx);
+            // Where it's known when this runs, the tempo is whatever the song's at by then.
+            let tick = self.timelines[context.song].0[idx];
+            if let Some(tick) = tick {
+                context.retempo(self.timelines[context.song].1.tempo_at(tick));
+            }
+            let inst = match &program.instructions[idx] {
+                OptionalInst::Instruction(inst) => inst,
+                // Nothing to convert in data, or after it.
+                _ => {
+                    out.extend(program.instructions[idx..range.end].iter().cloned());
+                    return None;
+                }
+            };
+            // A loop that gets its count from a prefix can't be unrolled, like one that runs forever.
+            let loop_count = match inst.unprefixed() {
+                Instruction::LoopStart(count) if inst.unprefixed() == inst => Some(*count),
+                Instruction::LoopStart(_) => Some(0),
+                _ => None
+            };
+            let mut inst = inst.clone();
+            let mut ends = false;
+            match &mut inst {
+                // The length isn't known until it runs, so the rounding can't carry on past it.
+                Instruction::Random { inst: prefixed, min, max } if matches!(**prefixed, Instruction::Rest(_) | Instruction::Note { .. }) => {
+                    let scale = |value: i16| self.length(value.max(0) as u64, &context).min(i16::MAX as u64) as i16;
+                    *min = scale(*min);
+                    *max = scale(*max);
+                },
+                Instruction::Variable { inst: prefixed, .. } if matches!(**prefixed, Instruction::Rest(_) | Instruction::Note { .. }) =>
+                    self.warn(idx, "The length comes from a variable, so it can't be converted"),
+                Instruction::Random { inst: prefixed, .. } | Instruction::Variable { inst: prefixed, .. }
+                    if matches!(**prefixed, Instruction::SetU16Param { param: U16Parameters::Tempo, .. }) =>
+                    self.warn(idx, "The tempo comes from a prefix, so it can't be converted"),
+                // A `?` might skip the wait, so the rounding can't carry on past it.
+                Instruction::Rest(len) if guarded => *len = self.length(*len, &context),
+                Instruction::Note { len, .. } if guarded || !context.note_wait => *len = self.length(*len, &context),
+                Instruction::Rest(len) | Instruction::Note { len, .. } => *len = self.wait(idx, *len, &mut context),
+                Instruction::SetU16Param { param: U16Parameters::Tempo, value } => {
+                    // Otherwise the song's tempo changes have it already.
+                    if tick.is_none() {
+                        if guarded {
+                            self.warn(idx, "Tempo change behind a ?, assuming it happens");
+                        }
+                        context.retempo(*value);
+                    }
+                    *value = self.target;
+                },
+                Instruction::SetU8Param { param: U8Parameters::Polyphony, value } => context.note_wait = *value != 0,
+                Instruction::Fork { dest, .. } => if let Some(target) = program.target(dest) {
+                    let id = self.copy_of(target, Context::start(context.song, context.tempo));
+                    *dest = self.destination(id);
+                },
+                Instruction::Jump(dest) => {
+                    if let Some(target) = program.target(dest) {
+                        let id = self.copy_of(target, context);
+                        next.push(id);
+                        *dest = self.destination(id);
+                    }
+                    ends = !guarded;
+                },
+                Instruction::Call(dest) => if let Some(target) = program.target(dest) {
+                    let id = self.copy_of(target, context);
+                    *dest = self.destination(id);
+                    let after = self.returns_of(id);
+                    if after.len() > 1 {
+                        self.warn(idx, "The subroutine can return with different tempos or rounding, assuming the first");
+                    }
+                    match after.first() {
+                        Some(after) if guarded && *after != context =>
+                            self.warn(idx, "A subroutine behind a ? changes the tempo or rounding, assuming it isn't called"),
+                        Some(after) => context = *after,
+                        None => ()
+                    }
+                },
+                Instruction::Return => {
+                    if !returns.contains(&context) {
+                        returns.push(context);
+                    }
+                    ends = !guarded;
+                },
+                Instruction::EndOfTrack => ends = !guarded,
+                _ if loop_count.is_some() => if let Some(end) = self.loop_end(idx, range.end) {
+                    let count = loop_count.unwrap_or(0);
+                    let mut body = Vec::new();
+                    let after = self.walk_range(idx + 1..end, context, &mut body, next, returns);
+                    match after {
+                        Some(after) if after != context && count != 0 && count <= MAX_UNROLL => {
+                            // Each time round is converted on its own.
+                            out.extend(body);
+                            let mut context = after;
+                            for _ in 1..count {
+                                let mut body = Vec::new();
+                                context = match self.walk_range(idx + 1..end, context, &mut body, next, returns) {
+                                    Some(context) => context,
+                                    None => unreachable!("the loop body got to the end the first time")
+                                };
+                                out.extend(body);
+                            }
+                            idx = end + 1;
+                            continue;
+                        },
+                        Some(after) if after != context => {
+                            self.warn(idx, "Each time round the loop rounds differently, so rounding errors build up");
+                            context = after;
+                        },
+                        Some(_) => (),
+                        // The body doesn't get to `end_loop`, so the loop doesn't matter.
+                        None => ()
+                    }
+                    out.push(OptionalInst::Instruction(inst));
+                    out.extend(body);
+                    out.push(program.instructions[end].clone());
+                    idx = end + 1;
+                    continue;
+                } else {
+                    self.warn(idx, "The loop goes past a label, so rounding errors might build up");
+                },
+                _ => ()
+            }
+            out.push(OptionalInst::Instruction(inst));
+            idx += 1;
+            if ends {
+                out.extend(program.instructions[idx..range.end].iter().cloned());
+                return None;
+            }
+        }
+        Some(context)
+    }
+}
+
+/// Changes every tempo in `program` to `target`, and every length to match, so that it sounds the same.
+///
+/// The tempo is the same for every track of a song, so each song's tempo changes are put together on one
+/// timeline first, the same way as `song_timing` does, and a change in one track stretches the waits of
+/// every track that's running at the time. Each track is then followed on its own path through the code,
+/// using that timeline for code that only runs once, and its own tempo changes everywhere else.
+/// Code reached at more than one tempo, or with different rounding carried over, is copied for each, so
+/// subroutines called under different tempos get a copy each and every track stays within a tick of exact.
+/// Copies go after everything else, with made up labels. Whatever no track reaches is left alone.
+pub fn convert(program: &Program, target: u16) -> Converted {
+    let map = TrackMap::new(program);
+    let mut converter = Converter {
+        program,
+        target,
+        timelines: (0..map.songs.len()).map(|song| {
+            let (ticks, tempo) = song_timeline(program, &map, song);
+            // Code that runs more than once, like a subroutine or a loop, can run at a different tempo each time.
+            let ticks = ticks.into_iter().map(|ticks| if ticks.len() == 1 { Some(ticks[0]) } else { None }).collect();
+            (ticks, tempo)
+        }).collect(),
+        copies: Vec::new(),
+        keys: HashMap::new(),
+        primary: HashMap::new(),
+        names: HashSet::new(),
+        warnings: BTreeSet::new()
+    };
+    for (song, &entry) in map.songs.iter().enumerate() {
+        converter.copy_of(entry, Context::start(song, DEFAULT_TEMPO));
+    }
+    while let Some(id) = converter.copies.iter().position(|copy| copy.walk == Walk::Pending) {
+        converter.walk(id);
+    }
+
+    let emit = |out: &mut Vec<OptionalInst>, id: usize, next_primary: Option<usize>| {
+        let copy = &converter.copies[id];
+        out.extend(copy.name.clone().map(OptionalInst::Label));
+        out.extend(copy.body.iter().cloned());
+        match copy.falls_into {
+            Some(into) if Some(into) != next_primary =>
+                out.push(OptionalInst::Instruction(Instruction::Jump(converter.destination(into)))),
+            _ => ()
+        }
+    };
+
+    let mut instructions = Vec::new();
+    let mut start = 0;
+    while start < program.len() {
+        let end = converter.section_end(start);
+        let next_primary = converter.primary.get(&end).cloned();
+        match converter.primary.get(&start) {
+            Some(&id) => emit(&mut instructions, id, next_primary),
+            None => instructions.extend(program.instructions[start..end].iter().cloned())
+        }
+        start = end;
+    }
+    // Copies go before the zero bytes that pad out the end.
+    let padding = instructions.iter().rev().take_while(|inst| **inst == OptionalInst::Byte(0)).count();
+    let padding = instructions.split_off(instructions.len() - padding);
+    let primaries: HashSet<usize> = converter.primary.values().cloned().collect();
+    for id in (0..converter.copies.len()).filter(|id| !primaries.contains(id)) {
+        emit(&mut instructions, id, None);
+    }
+    instructions.extend(padding);
+
+    Converted { instructions, warnings: converter.warnings.into_iter().collect() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::{assemble, to_string};
+    use crate::container::RSEQ;
+    use std::path::Path;
+
+    #[test]
+    fn test_convert() {
+        let source = "
+            .track 0 at main
+            .track 1 at other
+            main:
+                set Tempo = 120
+                rest 1
+                rest 1
+                call sub
+                set Tempo = 60
+                call sub
+                end_track
+            sub:
+                rest 10
+                ret
+            other:
+                start_loop 2
+                rest 1
+                end_loop
+                end_track
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let converted = convert(&Program::new(&rseq.instructions), 180);
+        let expected = "
+            .track 0 at main
+            .track 1 at other
+            main:
+                set Tempo = 180
+                rest 2
+                rest 1
+                call sub
+                set Tempo = 180
+                call sub_1
+                end_track
+            sub:
+                rest 15
+                ret
+            other:
+                rest 2
+                rest 1
+                end_track
+            sub_1:
+                rest 30
+                ret
+        ";
+        let expected = assemble(expected, Path::new("")).unwrap().rseq;
+        assert_eq!(to_string(&RSEQ { instructions: converted.instructions, generated_labels: Default::default() }), to_string(&expected));
+        assert_eq!(converted.warnings, []);
+
+        // The tracks are forked before track 0 sets the tempo, and its second change comes
+        // in the middle of track 1's rest.
+        let source = "
+            .track 0 at main
+            .track 1 at other
+            main:
+                set Tempo = 60
+                rest 10
+                set Tempo = 120
+                rest 10
+                end_track
+            other:
+                rest 20
+                end_track
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let converted = convert(&Program::new(&rseq.instructions), 120);
+        let expected = "
+            .track 0 at main
+            .track 1 at other
+            main:
+                set Tempo = 120
+                rest 20
+                set Tempo = 120
+                rest 10
+                end_track
+            other:
+                rest 30
+                end_track
+        ";
+        let expected = assemble(expected, Path::new("")).unwrap().rseq;
+        assert_eq!(to_string(&RSEQ { instructions: converted.instructions, generated_labels: Default::default() }), to_string(&expected));
+        assert_eq!(converted.warnings, []);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::analysis::{Program, Flow, tracks::TrackMap};
+use crate::instructions::{OptionalInst, Instruction, Destination, U16Parameters};
+use super::Problem;
+
+use std::collections::BTreeSet;
+
+/// Which tracks to leave out. Track 0 is never touched, since it's what starts all the others.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Tracks {
+    /// Tracks that don't get started at all.
+    pub drop: BTreeSet<u8>,
+    /// Tracks that still get started, but end straight away.
+    pub mute: BTreeSet<u8>,
+    /// If set, every other track is dropped.
+    pub keep: Option<BTreeSet<u8>>
+}
+
+impl Tracks {
+    fn drops(&self, track: u8) -> bool {
+        track != 0 && (self.drop.contains(&track) || self.keep.as_ref().is_some_and(|keep| !keep.contains(&track)))
+    }
+
+    fn mutes(&self, track: u8) -> bool {
+        track != 0 && self.mute.contains(&track)
+    }
+}
+
+/// The result of `select`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selected {
+    pub instructions: Vec<OptionalInst>,
+    /// How many instructions and labels were taken out.
+    pub removed: usize,
+    /// `TrackUsage`s that couldn't be updated.
+    pub warnings: Vec<Problem>
+}
+
+/// Takes tracks out of every song in `program`. Dropped tracks lose their `fork`s (and the `?` in front, if there is one)
+/// and their bit in every `TrackUsage`. Muted tracks get forked to an `end_track` instead of their own code.
+/// Anything no song can reach any more, like the code of the tracks that are gone, is removed too.
+pub fn select(program: &Program, tracks: &Tracks) -> Selected {
+    let songs = TrackMap::new(program).songs;
+    let padding = program.instructions.iter().rev().take_while(|inst| **inst == OptionalInst::Byte(0)).count();
+    let muted = (0..).map(|n| if n == 0 { "muted".to_string() } else { format!("muted_{}", n) })
+        .find(|name| program.label(name).is_none()).unwrap();
+    let dropped_mask = (0..16).filter(|&track| tracks.drops(track)).fold(0u16, |mask, track| mask | 1 << track);
+
+    let mut warnings = Vec::new();
+    let mut edited = Vec::with_capacity(program.len());
+    // Where each of the original instructions ended up in `edited`.
+    let mut moved = Vec::with_capacity(program.len());
+    let mut any_muted = false;
+    for (idx, item) in program.instructions[..program.len() - padding].iter().enumerate() {
+        moved.push(edited.len());
+        let mut item = item.clone();
+        match &mut item {
+            OptionalInst::Instruction(Instruction::Fork { track, .. }) if tracks.drops(*track) => {
+                if program.is_guarded(idx) {
+                    edited.pop();
+                }
+                continue;
+            },
+            OptionalInst::Instruction(Instruction::Fork { track, dest }) if tracks.mutes(*track) => {
+                *dest = Destination::Label(muted.clone());
+                any_muted = true;
+            },
+            OptionalInst::Instruction(Instruction::SetU16Param { param: U16Parameters::TrackUsage, value }) => *value &= !dropped_mask,
+            OptionalInst::Instruction(inst) => if let Instruction::SetU16Param { param: U16Parameters::TrackUsage, .. } = inst.unprefixed() {
+                if dropped_mask != 0 {
+                    warnings.push(Problem { idx, message: "TrackUsage comes from a prefix, so the dropped tracks are still in it".into() });
+                }
+            },
+            _ => ()
+        }
+        edited.push(item);
+    }
+    if any_muted {
+        edited.push(OptionalInst::Label(muted));
+        edited.push(OptionalInst::Instruction(Instruction::EndOfTrack));
+    }
+
+    // Keep only what the songs still reach.
+    let after = Program::new(&edited);
+    let mut reached = vec![false; after.len()];
+    let mut todo: Vec<usize> = songs.iter().map(|&song| moved[song]).collect();
+    while let Some(idx) = todo.pop() {
+        if idx >= after.len() || std::mem::replace(&mut reached[idx], true) {
+            continue;
+        }
+        for flow in after.successors(idx) {
+            match flow {
+                Flow::Next(next) | Flow::Jump(next) | Flow::Fork { target: next, .. } => todo.push(next),
+                Flow::Call { target, ret } => todo.extend(&[target, ret]),
+                Flow::Return => ()
+            }
+        }
+    }
+    let mut instructions: Vec<OptionalInst> = edited.iter().zip(&reached).filter(|(_, &reached)| reached).map(|(inst, _)| inst.clone()).collect();
+    let removed = program.len() - padding + usize::from(any_muted) * 2 - instructions.len();
+    instructions.extend(program.instructions[program.len() - padding..].iter().cloned());
+    Selected { instructions, removed, warnings }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::asm::{assemble, to_string};
+    use crate::container::RSEQ;
+    use std::path::Path;
+
+    #[test]
+    fn test_select() {
+        let source = "
+            main:
+                set TrackUsage = 15
+                fork 1, lead
+                process _0 == 1
+                ? fork 2, bass
+                fork 3, drums
+                note 60, 100, 96
+                end_track
+            lead:
+                note 72, 100, 96
+                end_track
+            bass:
+                call low
+                end_track
+            low:
+                note 36, 100, 96
+                ret
+            drums:
+                note 38, 100, 96
+                end_track
+        ";
+        let rseq = assemble(source, Path::new("")).unwrap().rseq;
+        let program = Program::new(&rseq.instructions);
+        let text = |selected: &Selected| to_string(&RSEQ { instructions: selected.instructions.clone(), generated_labels: Default::default() });
+
+        let tracks = Tracks { drop: [2].into(), mute: [3].into(), keep: None };
+        let selected = select(&program, &tracks);
+        assert_eq!(text(&selected).lines().collect::<Vec<_>>(), [
+            "main:", "set TrackUsage = 11", "fork 1, lead", "process _0 == 1", "fork 3, muted", "note 60, 100, 96", "end_track",
+            "lead:", "note 72, 100, 96", "end_track", "muted:", "end_track"
+        ]);
+        assert_eq!(selected.removed, 11);
+
+        let selected = select(&program, &Tracks { keep: Some([2].into()), ..Tracks::default() });
+        assert_eq!(text(&selected).lines().collect::<Vec<_>>(), [
+            "main:", "set TrackUsage = 5", "process _0 == 1", "? fork 2, bass", "note 60, 100, 96", "end_track",
+            "bass:", "call low", "end_track", "low:", "note 36, 100, 96", "ret"
+        ]);
+    }
+}